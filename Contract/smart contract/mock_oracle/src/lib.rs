@@ -0,0 +1,60 @@
+use anchor_lang::{prelude::*, solana_program::sysvar::clock::Clock};
+
+declare_id!("4oW3HmiK69HujSwvwMEM3hgQAntKrgxhkG94rwAj7TnV");
+
+#[program]
+pub mod mock_oracle{
+    use super::*;
+
+    pub fn init_oracle(
+        ctx : Context<InitOracle>,
+        ) -> ProgramResult {
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.authority = ctx.accounts.authority.key();
+        oracle.slot = 0;
+        oracle.result = [0;32];
+        Ok(())
+    }
+
+    pub fn fulfill(
+        ctx : Context<Fulfill>,
+        _result : [u8;32],
+        ) -> ProgramResult {
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.slot = (Clock::from_account_info(&ctx.accounts.clock)?).slot;
+        oracle.result = _result;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitOracle<'info>{
+    #[account(mut)]
+    authority : Signer<'info>,
+
+    #[account(init, payer=authority, space=8+ORACLE_RESULT_SIZE)]
+    oracle : ProgramAccount<'info, OracleResult>,
+
+    system_program : Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct Fulfill<'info>{
+    authority : Signer<'info>,
+
+    #[account(mut, has_one=authority)]
+    oracle : ProgramAccount<'info, OracleResult>,
+
+    clock : AccountInfo<'info>
+}
+
+pub const ORACLE_RESULT_SIZE : usize = 32+8+32;
+
+/// Layout expected by the raffle program: authority, then the slot the
+/// result was written at, then the 32 random bytes.
+#[account]
+pub struct OracleResult{
+    pub authority : Pubkey,
+    pub slot : u64,
+    pub result : [u8;32],
+}
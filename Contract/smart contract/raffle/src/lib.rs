@@ -1,8 +1,12 @@
 use borsh::{BorshDeserialize,BorshSerialize};
-use anchor_lang::{prelude::*, Discriminator, AnchorDeserialize, AnchorSerialize, Key, solana_program::{sysvar::{clock::Clock}, program::{invoke}}};
+use anchor_lang::{prelude::*, Discriminator, AnchorDeserialize, AnchorSerialize, Key, solana_program::{sysvar::{self, clock::Clock}, program::{invoke, invoke_signed}, system_instruction::{self, MAX_PERMITTED_DATA_LENGTH}, entrypoint::MAX_PERMITTED_DATA_INCREASE, program_pack::Pack, hash::{hash, hashv}, slot_hashes::MAX_ENTRIES}};
 use arrayref::{array_ref};
-use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer};
-use std::cell::Ref;
+use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer, CloseAccount, InitializeAccount};
+use anchor_spl::associated_token::{self, AssociatedToken, Create};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::native_mint;
+use bytemuck::{Pod, Zeroable};
+use std::{collections::{BTreeMap, BTreeSet}, mem::size_of};
 
 declare_id!("rafrZNbxGdfFUBzddkzgtcHLqijmjarEihYcUuCuByV");
 
@@ -21,30 +25,135 @@ pub mod raffle{
         raffle_system.token_mint = ctx.accounts.token_mint.key();
         raffle_system.token_account = ctx.accounts.token_account.key();
         raffle_system.pause_flag = false;
+        raffle_system.sol_fee_lamports = 0;
+        raffle_system.accrued_sol_fees = 0;
+        raffle_system.pending_owner = Pubkey::default();
+        raffle_system.pending_manager = Pubkey::default();
+        raffle_system.oracle_account = Pubkey::default();
         raffle_system.bump = _bump;
+        raffle_system.fee_bps = 0;
+        raffle_system.fee_recipient = ctx.accounts.owner.key();
         Ok(())
     }
 
-    pub fn transfer_authority(
+    pub fn set_fee(
+        ctx : Context<SetFee>,
+        _fee_bps : u16,
+        _fee_recipient : Pubkey,
+        )->ProgramResult{
+        if _fee_bps > MAX_FEE_BPS {
+            return Err(PoolError::InvalidFee.into());
+        }
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.fee_bps = _fee_bps;
+        raffle_system.fee_recipient = _fee_recipient;
+        Ok(())
+    }
+
+    /// Pins the oracle program and the one result account of it that
+    /// `request_randomness` will accept.
+    pub fn set_oracle_program(
+        ctx : Context<SetOracleProgram>,
+        _oracle_program : Pubkey,
+        _oracle_account : Pubkey,
+        )->ProgramResult{
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.oracle_program = _oracle_program;
+        raffle_system.oracle_account = _oracle_account;
+        Ok(())
+    }
+
+    /// First step of an ownership transfer. Nothing changes until
+    /// `_new_owner` signs `accept_owner`.
+    pub fn propose_owner(
         ctx : Context<TransferAuthority>,
         _new_owner : Pubkey,
         )->ProgramResult{
         let raffle_system = &mut ctx.accounts.raffle_system;
-        raffle_system.owner = _new_owner;
+        raffle_system.pending_owner = _new_owner;
+        Ok(())
+    }
+
+    pub fn accept_owner(
+        ctx : Context<AcceptOwner>,
+        )->ProgramResult{
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.owner = raffle_system.pending_owner;
+        raffle_system.pending_owner = Pubkey::default();
         Ok(())
     }
 
-    pub fn set_manager(
+    pub fn cancel_owner_transfer(
+        ctx : Context<TransferAuthority>,
+        )->ProgramResult{
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.pending_owner = Pubkey::default();
+        Ok(())
+    }
+
+    /// First step of a manager change. Nothing changes until
+    /// `_new_manager` signs `accept_manager`.
+    pub fn propose_manager(
+        ctx : Context<SetManager>,
+        _new_manager : Pubkey,
+        )->ProgramResult{
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.pending_manager = _new_manager;
+        Ok(())
+    }
+
+    pub fn accept_manager(
+        ctx : Context<AcceptManager>,
+        )->ProgramResult{
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.manager = raffle_system.pending_manager;
+        raffle_system.pending_manager = Pubkey::default();
+        Ok(())
+    }
+
+    pub fn cancel_manager_transfer(
         ctx : Context<SetManager>,
-        _new_owner : Pubkey,
         )->ProgramResult{
         let raffle_system = &mut ctx.accounts.raffle_system;
-        raffle_system.manager = _new_owner;
+        raffle_system.pending_manager = Pubkey::default();
+        Ok(())
+    }
+
+    /// Grants `member` the `PERMISSION_*` bits in `_permissions`.
+    pub fn init_role(
+        ctx : Context<InitRole>,
+        _bump : u8,
+        _permissions : u32,
+        )->ProgramResult{
+        let role = &mut ctx.accounts.role;
+        role.raffle_system = ctx.accounts.raffle_system.key();
+        role.member = *ctx.accounts.member.key;
+        role.permissions = _permissions;
+        role.bump = _bump;
+        Ok(())
+    }
+
+    pub fn set_role(
+        ctx : Context<SetRole>,
+        _permissions : u32,
+        )->ProgramResult{
+        let role = &mut ctx.accounts.role;
+        role.permissions = _permissions;
+        Ok(())
+    }
+
+    pub fn remove_role(
+        _ctx : Context<RemoveRole>,
+        )->ProgramResult{
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn init_raffle(
         ctx : Context<InitRaffle>,
+        _escrow_bump : u8,
+        _ledger_bump : u8,
+        _spots_bump : u8,
         _room_name : String,
         _logo : String,
         _discord : String,
@@ -53,7 +162,23 @@ pub mod raffle{
         _spot_num : u32,
         _max_ticket_num : u32,
         _max_ticket_per_user : u32,
+        _selection_mode : u8,
+        _manual_award : bool,
+        _min_ticket_num : u32,
+        _allow_early_end : bool,
+        _crank_reward : u64,
         ) -> ProgramResult {
+        if _selection_mode > SELECTION_DISTINCT_WALLET {
+            return Err(PoolError::InvalidSelectionMode.into());
+        }
+        if _spot_num == 0 {
+            msg!("Raffle needs at least one spot");
+            return Err(PoolError::InvalidIndex.into());
+        }
+        if _manual_award && _selection_mode != SELECTION_INDEPENDENT {
+            msg!("Manual award needs independent selection");
+            return Err(PoolError::InvalidManualAward.into());
+        }
         let raffle = &mut ctx.accounts.raffle;
         raffle.raffle_system = ctx.accounts.raffle_system.key();
         raffle.room_name = _room_name;
@@ -67,22 +192,81 @@ pub mod raffle{
         raffle.spots_account = *ctx.accounts.spot_store.key;
         raffle.is_show = true;
         raffle.max_ticket_per_user = _max_ticket_per_user;
-
-        let mut ledger_data = (&mut ctx.accounts.ledger).data.borrow_mut();
-        let mut new_data = Ledger::discriminator().try_to_vec().unwrap();
-        new_data.append(&mut raffle.key().try_to_vec().unwrap());
-        new_data.append(&mut (0 as u32).try_to_vec().unwrap());
-        for i in 0..new_data.len(){
-            ledger_data[i] = new_data[i];
+        raffle.selection_mode = _selection_mode;
+        raffle.manual_award = _manual_award;
+        raffle.min_ticket_num = _min_ticket_num;
+        raffle.payment_mint = ctx.accounts.payment_mint.key();
+        raffle.creator = ctx.accounts.owner.key();
+        raffle.allow_early_end = _allow_early_end;
+        raffle.crank_reward = _crank_reward;
+        raffle.settled_revenue = 0;
+        raffle.reveal_slot = 0;
+        raffle.treasury_proceeds = false;
+
+        let raffle_key = raffle.key();
+        let ledger_size = ledger_size(ledger_capacity(_max_ticket_num));
+        create_store(
+            CreateStoreParams{
+                payer : ctx.accounts.owner.to_account_info(),
+                store : ctx.accounts.ledger.clone(),
+                system : ctx.accounts.system_program.to_account_info(),
+                lamports : ctx.accounts.rent.minimum_balance(ledger_size),
+                seeds : &[raffle_key.as_ref(), LEDGER_SEED, &[_ledger_bump]],
+                size : ledger_size,
+                owner : ID,
+            }
+        )?;
+        let spot_store_size = spot_store_size(_spot_num);
+        create_store(
+            CreateStoreParams{
+                payer : ctx.accounts.owner.to_account_info(),
+                store : ctx.accounts.spot_store.clone(),
+                system : ctx.accounts.system_program.to_account_info(),
+                lamports : ctx.accounts.rent.minimum_balance(spot_store_size),
+                seeds : &[raffle_key.as_ref(), SPOT_STORE_SEED, &[_spots_bump]],
+                size : spot_store_size,
+                owner : ID,
+            }
+        )?;
+        if raffle.payment_mint == native_mint::ID {
+            raffle.escrow_account = Pubkey::default();
+        } else {
+            create_store(
+                CreateStoreParams{
+                    payer : ctx.accounts.owner.to_account_info(),
+                    store : ctx.accounts.escrow_account.clone(),
+                    system : ctx.accounts.system_program.to_account_info(),
+                    lamports : ctx.accounts.rent.minimum_balance(spl_token::state::Account::LEN),
+                    seeds : &[raffle_key.as_ref(), ESCROW_SEED, &[_escrow_bump]],
+                    size : spl_token::state::Account::LEN,
+                    owner : token::ID,
+                }
+            )?;
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.token_program.to_account_info().clone(),
+                InitializeAccount{
+                    account : ctx.accounts.escrow_account.clone(),
+                    mint : ctx.accounts.payment_mint.to_account_info().clone(),
+                    authority : ctx.accounts.raffle_system.to_account_info().clone(),
+                    rent : ctx.accounts.rent.to_account_info().clone(),
+                }
+            );
+            token::initialize_account(cpi_ctx)?;
+            raffle.escrow_account = *ctx.accounts.escrow_account.key;
         }
 
-        let mut spots_data = (&mut ctx.accounts.spot_store).data.borrow_mut();
-        let mut spots_new_data: Vec<u8> = SpotStore::discriminator().try_to_vec().unwrap();
-        spots_new_data.append(&mut raffle.key().try_to_vec().unwrap());
-        spots_new_data.append(&mut _spot_num.try_to_vec().unwrap());
-        for i in 0..spots_new_data.len(){
-            spots_data[i] = spots_new_data[i];
-        }
+        let mut ledger_data = ctx.accounts.ledger.data.borrow_mut();
+        ledger_data[..8].copy_from_slice(&Ledger::discriminator());
+        let ledger = load_header_mut::<Ledger>(&mut ledger_data)?;
+        ledger.raffle_account = raffle_key;
+        ledger.count = 0;
+        ledger.range_num = 0;
+
+        let mut spots_data = ctx.accounts.spot_store.data.borrow_mut();
+        spots_data[..8].copy_from_slice(&SpotStore::discriminator());
+        let spot_store = load_header_mut::<SpotStore>(&mut spots_data)?;
+        spot_store.raffle_account = raffle_key;
+        spot_store.spot_num = _spot_num;
 
         Ok(())
     }
@@ -91,11 +275,16 @@ pub mod raffle{
         ctx : Context<InitUserData>,
         _bump : u8
         ) -> ProgramResult {
+        if ctx.accounts.raffle_system.pause_flag {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
         let user_data =  &mut ctx.accounts.user_data;
         user_data.owner = ctx.accounts.owner.key();
         user_data.raffle = ctx.accounts.raffle.key();
         user_data.ticket_num = 0;
         user_data.bump = _bump;
+        user_data.refunded = false;
         Ok(())
     }
 
@@ -114,10 +303,27 @@ pub mod raffle{
         Ok(())
     }
 
+    /// Lets the creator of a pending SPL raffle send its proceeds to the
+    /// system `token_account` instead of their own token account.
+    pub fn set_treasury_proceeds(
+        ctx : Context<SetTreasuryProceeds>,
+        _treasury_proceeds : bool,
+        ) -> ProgramResult {
+        let raffle = &mut ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Pending])?;
+        if raffle.payment_mint == native_mint::ID {
+            msg!("SOL raffles always pay the creator");
+            return Err(PoolError::NotMatch.into());
+        }
+        raffle.treasury_proceeds = _treasury_proceeds;
+        Ok(())
+    }
+
     pub fn put_spot(
         ctx : Context<PutSpot>,
         _index : u32,
         ) -> ProgramResult {
+        ctx.accounts.raffle.check_status(&[RaffleStatus::Pending])?;
         let cpi_ctx = CpiContext::new(
             ctx.accounts.token_program.to_account_info().clone(),
             Transfer{
@@ -129,9 +335,9 @@ pub mod raffle{
         token::transfer(cpi_ctx, 1)?;
         set_spot(&mut ctx.accounts.spot_store, _index as usize, Spot{
             nft : ctx.accounts.nft.key(),
-            winner_ticket : 0,
-            claimed : false,
-        });
+            winner_ticket : NO_WINNER,
+            claimed : 0,
+        })?;
         Ok(())
     }
 
@@ -139,10 +345,14 @@ pub mod raffle{
         ctx : Context<RedeemSpot>,
         _index : u32,
         ) -> ProgramResult {
+        ctx.accounts.raffle.check_status(&[RaffleStatus::Pending, RaffleStatus::Drawn, RaffleStatus::Cancelled, RaffleStatus::Failed])?;
         let spot = get_spot(&ctx.accounts.spot_store, _index as usize)?;
         if spot.nft != ctx.accounts.nft.key(){
             return Err(PoolError::NotMatch.into());
         }
+        if ctx.accounts.raffle.status == RaffleStatus::Drawn && ({ spot.winner_ticket } != NO_WINNER || spot.claimed != 0) {
+            return Err(PoolError::InvalidStatus.into());
+        }
         let raffle_system = &mut ctx.accounts.raffle_system;
         let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
         let signer = &[&raffle_system_seeds[..]];
@@ -156,34 +366,61 @@ pub mod raffle{
             signer
         );
         token::transfer(cpi_ctx, 1)?;
+        if ctx.accounts.nft_from.amount == 1 {
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info().clone(),
+                CloseAccount{
+                    account : ctx.accounts.nft_from.to_account_info().clone(),
+                    destination : ctx.accounts.creator.clone(),
+                    authority : raffle_system.to_account_info().clone(),
+                },
+                signer
+            );
+            token::close_account(cpi_ctx)?;
+        }
         set_spot(&mut ctx.accounts.spot_store, _index as usize, Spot{
             nft : Pubkey::default(),
-            winner_ticket : 0,
-            claimed : false,
-        });
+            winner_ticket : NO_WINNER,
+            claimed : 0,
+        })?;
         Ok(())
     }
 
     pub fn start_raffle(
         ctx : Context<StartRaffle>,
         _period : u64,
+        _commitment : [u8;32],
         ) -> ProgramResult {
         let raffle = &mut ctx.accounts.raffle;
-        if raffle.status != 0 {
-            return Err(PoolError::InvalidStatus.into());
+        if raffle.manual_award && _commitment != [0;32] {
+            msg!("Manual award raffles cannot be revealed");
+            return Err(PoolError::InvalidManualAward.into());
+        }
+        if !raffle.manual_award && _commitment == [0;32] && ctx.accounts.raffle_system.oracle_program == Pubkey::default() {
+            msg!("Raffle needs a commitment, an oracle or manual award");
+            return Err(PoolError::NoRandomnessSource.into());
         }
+        raffle.transition(RaffleStatus::Open)?;
         raffle.start_time = (Clock::from_account_info(&ctx.accounts.clock)?).unix_timestamp as u64;
         raffle.period = _period;
-        raffle.status = 1;
+        raffle.commitment = _commitment;
+        raffle.reveal_slot = 0;
+        raffle.oracle = Pubkey::default();
+        raffle.oracle_request_slot = 0;
         Ok(())
     }
 
     pub fn buy_ticket(
         ctx : Context<BuyTicket>,
         _num : u32,
-        _value : u64
         ) -> ProgramResult {
-
+        if ctx.accounts.raffle_system.pause_flag {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        if _num == 0 {
+            return Err(PoolError::InvalidTicketNum.into());
+        }
         let raffle = &mut ctx.accounts.raffle;
         let raffle_system = &mut ctx.accounts.raffle_system;
         let user_data = &mut ctx.accounts.user_data;
@@ -197,69 +434,321 @@ pub mod raffle{
                 return Err(PoolError::OverflowTicketNumPerUser.into());
             }  
         }
-        let current_user_num = get_num(&ctx.accounts.ledger.data.borrow())?;
-        if raffle.status != 1{
-            msg!("Invalid status");
-            return Err(PoolError::InvalidStatus.into());
-        }
+        let current_user_num = get_num(&ctx.accounts.ledger)?;
+        raffle.check_status(&[RaffleStatus::Open])?;
         let clock = (Clock::from_account_info(&ctx.accounts.clock)?).unix_timestamp as u64;
-        if clock > raffle.start_time + raffle.period{
+        if clock > raffle.start_time + raffle.period || raffle.reveal_slot != 0 {
             return Err(PoolError::TimeOut.into());
         }
         if (current_user_num + _num as usize) > raffle.max_ticket_num as usize{
             return Err(PoolError::Overflow.into());
         }
 
-        let cpi_ctx = CpiContext::new(
-            ctx.accounts.token_program.to_account_info().clone(),
-            Transfer{
-                from : ctx.accounts.token_from.to_account_info().clone(),
-                to : ctx.accounts.token_to.to_account_info().clone(),
-                authority : ctx.accounts.owner.to_account_info().clone()    
-            }
-        );
-
-        token::transfer(cpi_ctx, raffle.ticket_value * _num as u64)?;
+        let amount = raffle.ticket_value.checked_mul(_num as u64).ok_or(PoolError::NumericalOverflowError)?;
+        if raffle.payment_mint == native_mint::ID {
+            sol_transfer_to_pool(
+                SolTransferToPoolParams{
+                    source : ctx.accounts.owner.to_account_info().clone(),
+                    destination : raffle.to_account_info(),
+                    system : ctx.accounts.system_program.to_account_info().clone(),
+                    amount
+                }
+            )?;
+            raffle.sol_balance = raffle.sol_balance.checked_add(amount).ok_or(PoolError::NumericalOverflowError)?;
+        } else {
+            check_token_account(&ctx.accounts.token_from, &ctx.accounts.owner.key(), &raffle.payment_mint)?;
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.token_program.to_account_info().clone(),
+                Transfer{
+                    from : ctx.accounts.token_from.to_account_info().clone(),
+                    to : ctx.accounts.token_to.to_account_info().clone(),
+                    authority : ctx.accounts.owner.to_account_info().clone()    
+                }
+            );
+            token::transfer(cpi_ctx, amount)?;
+        }
 
-        if raffle_system.pause_flag {
+        if raffle_system.sol_fee_lamports != 0 {
             sol_transfer_to_pool(
                 SolTransferToPoolParams{
                     source : ctx.accounts.owner.to_account_info().clone(),
-                    destination : ctx.accounts.raffle_system.clone(),
+                    destination : raffle_system.to_account_info(),
                     system : ctx.accounts.system_program.to_account_info().clone(),
-                    amount : _value
+                    amount : raffle_system.sol_fee_lamports
                 }
             )?;
+            raffle_system.accrued_sol_fees = raffle_system.accrued_sol_fees.checked_add(raffle_system.sol_fee_lamports).ok_or(PoolError::NumericalOverflowError)?;
         }
 
-        set_user(&mut ctx.accounts.ledger, current_user_num as usize, ctx.accounts.owner.key(), _num);
-        set_count(&mut ctx.accounts.ledger, current_user_num as u32 + _num);
+        push_tickets(&mut ctx.accounts.ledger, ctx.accounts.owner.key(), _num)?;
 
         user_data.ticket_num += _num;
         Ok(())
     }
 
+    /// Makes room for `_range_num` more ticket ranges in the ledger. The
+    /// caller pays the extra rent, so large raffles only pay for the
+    /// ledger space their sales actually need.
+    ///
+    /// Needs a cluster whose BPF loader accepts data length changes made by
+    /// the program (realloc support). Older loaders reject the transaction
+    /// with `AccountDataSizeChanged` and the ledger keeps its size, so
+    /// raffles there are limited to the initial `ledger_capacity`.
+    pub fn grow_ledger(
+        ctx : Context<GrowLedger>,
+        _range_num : u32,
+        ) -> ProgramResult {
+        if ctx.accounts.raffle_system.pause_flag {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        let raffle = &ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Pending, RaffleStatus::Open])?;
+        let ledger = &ctx.accounts.ledger;
+        let current_size = ledger.data_len();
+        let range_num = ((current_size - LEDGER_HEADER_SIZE) / size_of::<TicketRange>()) as u32;
+        let new_range_num = range_num.checked_add(_range_num).ok_or(PoolError::Overflow)?;
+        if new_range_num > raffle.max_ticket_num {
+            msg!("Ledger already holds a range per ticket");
+            return Err(PoolError::StoreTooLarge.into());
+        }
+        let new_size = ledger_size(new_range_num);
+        if new_size > MAX_PERMITTED_DATA_LENGTH as usize {
+            return Err(PoolError::StoreTooLarge.into());
+        }
+
+        let lamports = ctx.accounts.rent.minimum_balance(new_size).saturating_sub(ledger.lamports());
+        if lamports != 0 {
+            sol_transfer_to_pool(
+                SolTransferToPoolParams{
+                    source : ctx.accounts.payer.to_account_info().clone(),
+                    destination : ledger.clone(),
+                    system : ctx.accounts.system_program.to_account_info().clone(),
+                    amount : lamports
+                }
+            )?;
+        }
+        resize_store(ledger, new_size)
+    }
+
+    /// Ends the sale. A commit-reveal raffle only records the current slot
+    /// here: its hash is unknown until the slot is produced, so the creator
+    /// cannot grind the reveal against it. If the reveal does not land while
+    /// that hash is still in SlotHashes, the raffle fails and buyers get
+    /// refunds.
+    ///
+    /// Only manual award raffles are drawn here, keeping the winners set by
+    /// `end_state`. Other raffles must be drawn by reveal or by the oracle;
+    /// an oracle raffle not drawn within DRAW_TIMEOUT of the end of its
+    /// period fails so buyers can get refunds.
     pub fn end_raffle(
         ctx : Context<EndRaffle>
         ) -> ProgramResult {
+        let raffle_system = &ctx.accounts.raffle_system;
         let raffle = &mut ctx.accounts.raffle;
-        let ticket_num = get_num(&ctx.accounts.ledger.data.borrow())?;
-        let clock = (Clock::from_account_info(&ctx.accounts.clock)?).unix_timestamp as u64;
+        let is_owner = ctx.accounts.cranker.key() == raffle_system.owner;
+        if raffle_system.pause_flag && !is_owner {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        raffle.check_status(&[RaffleStatus::Open])?;
+        let clock_data = Clock::from_account_info(&ctx.accounts.clock)?;
+        let clock = clock_data.unix_timestamp as u64;
+        let elapsed = clock > raffle.start_time + raffle.period;
+        if !(elapsed || (is_owner && raffle.allow_early_end)) {
+            msg!("Raffle period has not elapsed");
+            return Err(PoolError::RaffleNotEnded.into());
+        }
+        if raffle.commitment != [0;32] {
+            if raffle.reveal_slot == 0 {
+                raffle.reveal_slot = clock_data.slot;
+                return Ok(());
+            }
+            if clock_data.slot > raffle.reveal_slot + MAX_ENTRIES as u64 {
+                msg!("Reveal window missed");
+                raffle.transition(RaffleStatus::Failed)?;
+                return Ok(());
+            }
+            msg!("Raffle must be ended by reveal");
+            return Err(PoolError::RevealRequired.into());
+        }
+        let ticket_num = get_num(&ctx.accounts.ledger)?;
+        if ticket_num < raffle.min_ticket_num as usize {
+            msg!("Minimum ticket number not reached");
+            raffle.transition(RaffleStatus::Failed)?;
+            return Ok(());
+        }
         if ticket_num!=0{
-            let mut winners : Vec<u32> = vec![];
-            for i in 0..raffle.spot_num{
-                let rand = ((clock+i as u64)*(i as u64 + 1)) % ticket_num as u64;
-                let spot = get_spot(&ctx.accounts.spot_store, i as usize)?;
-                if spot.winner_ticket != 0 {
-                    winners.push(spot.winner_ticket as u32);
-                } else {
-                    winners.push(rand as u32);
+            if !raffle.manual_award {
+                if clock > raffle.start_time + raffle.period + DRAW_TIMEOUT {
+                    msg!("Oracle draw timed out");
+                    raffle.transition(RaffleStatus::Failed)?;
+                    return Ok(());
                 }
+                msg!("Raffle must be ended by oracle randomness");
+                return Err(PoolError::OracleRequested.into());
+            }
+            if has_unawarded_spot(&ctx.accounts.spot_store)? {
+                msg!("Manual award not set for every spot");
+                raffle.transition(RaffleStatus::Failed)?;
+                return Ok(());
+            }
+            raffle.transition(RaffleStatus::Drawn)?;
+            if elapsed {
+                pay_crank_reward(
+                    CrankRewardParams{
+                        raffle_system,
+                        raffle,
+                        cranker : ctx.accounts.cranker.to_account_info(),
+                        token_from : ctx.accounts.token_from.clone(),
+                        token_to : ctx.accounts.token_to.clone(),
+                        token_program : ctx.accounts.token_program.to_account_info(),
+                    }
+                )?;
             }
-            set_winner(&mut ctx.accounts.spot_store, winners);
-            raffle.status = 2;
         }else{
-            raffle.status = 0;
+            raffle.transition(RaffleStatus::Pending)?;
+        }
+        Ok(())
+    }
+
+    pub fn reveal_raffle(
+        ctx : Context<RevealRaffle>,
+        _secret : [u8;32],
+        ) -> ProgramResult {
+        let raffle = &mut ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Open])?;
+        if raffle.commitment == [0;32] || hash(&_secret).to_bytes() != raffle.commitment {
+            msg!("Secret does not match commitment");
+            return Err(PoolError::InvalidReveal.into());
+        }
+        if raffle.reveal_slot == 0 {
+            msg!("Sale not closed by end_raffle");
+            return Err(PoolError::RaffleNotEnded.into());
+        }
+        let ticket_num = get_num(&ctx.accounts.ledger)?;
+        if ticket_num < raffle.min_ticket_num as usize {
+            msg!("Minimum ticket number not reached");
+            raffle.transition(RaffleStatus::Failed)?;
+            return Ok(());
+        }
+        if ticket_num!=0{
+            let slot_hash = get_slot_hash(&ctx.accounts.slot_hashes, raffle.reveal_slot)?;
+            let seed = reveal_seed(&_secret, &slot_hash);
+            let winners = draw_winners(&seed, &ctx.accounts.ledger, ticket_num as u32, raffle.spot_num, raffle.selection_mode)?;
+            set_winner(&mut ctx.accounts.spot_store, winners)?;
+            raffle.transition(RaffleStatus::Drawn)?;
+            pay_crank_reward(
+                CrankRewardParams{
+                    raffle_system : &ctx.accounts.raffle_system,
+                    raffle,
+                    cranker : ctx.accounts.owner.to_account_info(),
+                    token_from : ctx.accounts.token_from.clone(),
+                    token_to : ctx.accounts.token_to.clone(),
+                    token_program : ctx.accounts.token_program.to_account_info(),
+                }
+            )?;
+        }else{
+            raffle.transition(RaffleStatus::Pending)?;
+        }
+        Ok(())
+    }
+
+    /// Asks the pinned oracle for the draw seed once the sale is over. A
+    /// raffle gets a single request, so the result cannot be re-rolled.
+    /// Anyone can send it, as with `end_raffle`.
+    pub fn request_randomness(
+        ctx : Context<RequestRandomness>,
+        ) -> ProgramResult {
+        let raffle_system = &ctx.accounts.raffle_system;
+        let raffle = &mut ctx.accounts.raffle;
+        if raffle_system.pause_flag && ctx.accounts.cranker.key() != raffle_system.owner {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        raffle.check_status(&[RaffleStatus::Open])?;
+        if raffle.commitment != [0;32] {
+            msg!("Raffle must be ended by reveal");
+            return Err(PoolError::RevealRequired.into());
+        }
+        if raffle.manual_award {
+            msg!("Manual award raffles cannot use the oracle");
+            return Err(PoolError::InvalidManualAward.into());
+        }
+        if raffle.oracle != Pubkey::default() {
+            msg!("Randomness already requested");
+            return Err(PoolError::OracleRequested.into());
+        }
+        if raffle_system.oracle_program == Pubkey::default()
+            || *ctx.accounts.oracle.owner != raffle_system.oracle_program
+            || *ctx.accounts.oracle.key != raffle_system.oracle_account {
+            msg!("Invalid oracle");
+            return Err(PoolError::InvalidOracle.into());
+        }
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        if clock.unix_timestamp as u64 <= raffle.start_time + raffle.period {
+            msg!("Raffle period has not elapsed");
+            return Err(PoolError::RaffleNotEnded.into());
+        }
+        raffle.oracle = *ctx.accounts.oracle.key;
+        raffle.oracle_request_slot = clock.slot;
+        Ok(())
+    }
+
+    /// Draws the winners from the oracle result. Anyone can send it and
+    /// receives the crank reward.
+    pub fn consume_randomness(
+        ctx : Context<ConsumeRandomness>,
+        ) -> ProgramResult {
+        let raffle_system = &ctx.accounts.raffle_system;
+        let raffle = &mut ctx.accounts.raffle;
+        if raffle_system.pause_flag && ctx.accounts.cranker.key() != raffle_system.owner {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        raffle.check_status(&[RaffleStatus::Open])?;
+        if raffle.oracle == Pubkey::default() || *ctx.accounts.oracle.owner != raffle_system.oracle_program {
+            msg!("Invalid oracle");
+            return Err(PoolError::InvalidOracle.into());
+        }
+        let (result_slot, result) = get_oracle_result(&ctx.accounts.oracle)?;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        if clock.unix_timestamp as u64 <= raffle.start_time + raffle.period {
+            msg!("Raffle period has not elapsed");
+            return Err(PoolError::RaffleNotEnded.into());
+        }
+        let slot = clock.slot;
+        if result_slot <= raffle.oracle_request_slot {
+            msg!("Oracle result not written since the request");
+            return Err(PoolError::StaleOracleResult.into());
+        }
+        if slot > result_slot + ORACLE_MAX_AGE {
+            msg!("Stale oracle result");
+            raffle.transition(RaffleStatus::Failed)?;
+            return Ok(());
+        }
+        let ticket_num = get_num(&ctx.accounts.ledger)?;
+        if ticket_num < raffle.min_ticket_num as usize {
+            msg!("Minimum ticket number not reached");
+            raffle.transition(RaffleStatus::Failed)?;
+            return Ok(());
+        }
+        if ticket_num!=0{
+            let winners = draw_winners(&result, &ctx.accounts.ledger, ticket_num as u32, raffle.spot_num, raffle.selection_mode)?;
+            set_winner(&mut ctx.accounts.spot_store, winners)?;
+            raffle.transition(RaffleStatus::Drawn)?;
+            pay_crank_reward(
+                CrankRewardParams{
+                    raffle_system,
+                    raffle,
+                    cranker : ctx.accounts.cranker.to_account_info(),
+                    token_from : ctx.accounts.token_from.clone(),
+                    token_to : ctx.accounts.token_to.clone(),
+                    token_program : ctx.accounts.token_program.to_account_info(),
+                }
+            )?;
+        }else{
+            raffle.transition(RaffleStatus::Pending)?;
         }
         Ok(())
     }
@@ -269,19 +758,203 @@ pub mod raffle{
         _ticket_count : u64
         ) -> ProgramResult {
         let raffle = &mut ctx.accounts.raffle;
-        let ticket_num = get_num(&ctx.accounts.ledger.data.borrow())?;
+        raffle.check_status(&[RaffleStatus::Open])?;
+        if !raffle.manual_award {
+            msg!("Raffle was created in random mode");
+            return Err(PoolError::ManualAwardDisabled.into());
+        }
+        let ticket_num = get_num(&ctx.accounts.ledger)?;
         if ticket_num!=0{
+            if _ticket_count >= ticket_num as u64 {
+                return Err(PoolError::IndexGreaterThanLength.into());
+            }
             let mut winners : Vec<u32> = vec![];
             for _i in 0..raffle.spot_num{
                 let rand = _ticket_count;
                 winners.push(rand as u32);
             }
-            set_winner(&mut ctx.accounts.spot_store, winners);
+            set_winner(&mut ctx.accounts.spot_store, winners)?;
+            emit!(ManualAwardEvent{
+                raffle : raffle.key(),
+                manager : ctx.accounts.owner.key(),
+                winner_ticket : _ticket_count as u32,
+            });
+        }
+        Ok(())
+    }
+
+    /// Cancels a raffle before its draw. Once `end_raffle` recorded the
+    /// reveal slot or randomness was requested, the outcome may already be
+    /// known, so it can no longer be cancelled.
+    pub fn cancel_raffle(
+        ctx : Context<CancelRaffle>,
+        ) -> ProgramResult {
+        let raffle = &mut ctx.accounts.raffle;
+        if raffle.status == RaffleStatus::Open && (raffle.reveal_slot != 0 || raffle.oracle != Pubkey::default()) {
+            msg!("Draw already under way");
+            return Err(PoolError::InvalidStatus.into());
+        }
+        raffle.transition(RaffleStatus::Cancelled)?;
+        Ok(())
+    }
+
+    pub fn refund_tickets(
+        ctx : Context<RefundTickets>,
+        ) -> ProgramResult {
+        if ctx.accounts.raffle_system.pause_flag {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
+        let raffle_system = &ctx.accounts.raffle_system;
+        let raffle = &mut ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Cancelled, RaffleStatus::Failed])?;
+        let user_data = &mut ctx.accounts.user_data;
+        if user_data.refunded {
+            return Err(PoolError::AlreadyRefunded.into());
+        }
+        let amount = raffle.ticket_value.checked_mul(user_data.ticket_num as u64).ok_or(PoolError::NumericalOverflowError)?;
+        if raffle.payment_mint == native_mint::ID {
+            sol_transfer(&raffle.to_account_info(), &ctx.accounts.owner.to_account_info(), amount)?;
+            raffle.sol_balance -= amount;
+        } else {
+            check_token_account(&ctx.accounts.token_to, &ctx.accounts.owner.key(), &raffle.payment_mint)?;
+            let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
+            let signer = &[&raffle_system_seeds[..]];
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info().clone(),
+                Transfer{
+                    from : ctx.accounts.token_from.to_account_info().clone(),
+                    to : ctx.accounts.token_to.to_account_info().clone(),
+                    authority : raffle_system.to_account_info().clone(),
+                },
+                signer
+            );
+            token::transfer(cpi_ctx, amount)?;
+        }
+        user_data.refunded = true;
+        Ok(())
+    }
+
+    pub fn settle_raffle(
+        ctx : Context<SettleRaffle>,
+        ) -> ProgramResult {
+        let raffle_system = &ctx.accounts.raffle_system;
+        let raffle = &mut ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Drawn])?;
+        if raffle.payment_mint == native_mint::ID {
+            if *ctx.accounts.token_to.key != raffle.creator || *ctx.accounts.fee_to.key != raffle_system.fee_recipient {
+                return Err(PoolError::NotMatch.into());
+            }
+            let (fee, proceeds) = split_fee(raffle.sol_balance, raffle_system.fee_bps);
+            sol_transfer(&raffle.to_account_info(), &ctx.accounts.fee_to, fee)?;
+            sol_transfer(&raffle.to_account_info(), &ctx.accounts.token_to, proceeds)?;
+            raffle.sol_balance = 0;
+            return Ok(());
+        }
+        if raffle.treasury_proceeds {
+            if *ctx.accounts.token_to.key != raffle_system.token_account {
+                return Err(PoolError::NotMatch.into());
+            }
+        } else {
+            check_token_account(&ctx.accounts.token_to, &raffle.creator, &raffle.payment_mint)?;
+        }
+        let (fee, proceeds) = split_fee(Account::<TokenAccount>::try_from(&ctx.accounts.token_from)?.amount, raffle_system.fee_bps);
+        let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
+        let signer = &[&raffle_system_seeds[..]];
+        if fee != 0 {
+            check_token_account(&ctx.accounts.fee_to, &raffle_system.fee_recipient, &raffle.payment_mint)?;
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info().clone(),
+                Transfer{
+                    from : ctx.accounts.token_from.to_account_info().clone(),
+                    to : ctx.accounts.fee_to.to_account_info().clone(),
+                    authority : raffle_system.to_account_info().clone(),
+                },
+                signer
+            );
+            token::transfer(cpi_ctx, fee)?;
+        }
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info().clone(),
+            Transfer{
+                from : ctx.accounts.token_from.to_account_info().clone(),
+                to : ctx.accounts.token_to.to_account_info().clone(),
+                authority : raffle_system.to_account_info().clone(),
+            },
+            signer
+        );
+        token::transfer(cpi_ctx, proceeds)?;
+        if raffle.treasury_proceeds {
+            raffle.settled_revenue = raffle.settled_revenue.checked_add(proceeds).ok_or(PoolError::NumericalOverflowError)?;
+        }
+        Ok(())
+    }
+
+    /// Closes a finished raffle along with its ledger, spot store and
+    /// payment escrow, returning their rent to the creator. Every spot must
+    /// be claimed or redeemed, which also closes its NFT escrow, and all
+    /// proceeds settled or refunded first.
+    pub fn close_raffle(
+        ctx : Context<CloseRaffle>,
+        ) -> ProgramResult {
+        let raffle_system = &ctx.accounts.raffle_system;
+        let raffle = &ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Drawn, RaffleStatus::Cancelled, RaffleStatus::Failed])?;
+        if raffle.sol_balance != 0 {
+            msg!("Raffle proceeds not settled or refunded");
+            return Err(PoolError::EscrowNotEmpty.into());
+        }
+        if raffle.payment_mint != native_mint::ID && Account::<TokenAccount>::try_from(&ctx.accounts.escrow_account)?.amount != 0 {
+            msg!("Raffle proceeds not settled or refunded");
+            return Err(PoolError::EscrowNotEmpty.into());
+        }
+        if raffle.settled_revenue != 0 {
+            msg!("Settled revenue not redeemed");
+            return Err(PoolError::EscrowNotEmpty.into());
         }
-        
+        check_spots_closed(&ctx.accounts.spot_store)?;
+
+        if raffle.payment_mint != native_mint::ID {
+            let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
+            let signer = &[&raffle_system_seeds[..]];
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info().clone(),
+                CloseAccount{
+                    account : ctx.accounts.escrow_account.clone(),
+                    destination : ctx.accounts.creator.clone(),
+                    authority : raffle_system.to_account_info().clone(),
+                },
+                signer
+            );
+            token::close_account(cpi_ctx)?;
+        }
+        close_store(&ctx.accounts.ledger, &ctx.accounts.creator)?;
+        close_store(&ctx.accounts.spot_store, &ctx.accounts.creator)?;
         Ok(())
     }
 
+    /// Closes a buyer's `UserData` once it can no longer be needed: the
+    /// raffle was drawn, its tickets were refunded, it never held any, or
+    /// the raffle itself is already closed.
+    pub fn close_user_data(
+        ctx : Context<CloseUserData>,
+        ) -> ProgramResult {
+        let raffle = &ctx.accounts.raffle;
+        if *raffle.owner != ID || raffle.data_is_empty() {
+            return Ok(());
+        }
+        let raffle = ProgramAccount::<Raffle>::try_from(&ID, raffle)?;
+        let user_data = &ctx.accounts.user_data;
+        if raffle.status == RaffleStatus::Drawn {
+            return Ok(());
+        }
+        if user_data.ticket_num == 0 || user_data.refunded {
+            return raffle.check_status(&[RaffleStatus::Pending, RaffleStatus::Cancelled, RaffleStatus::Failed]);
+        }
+        msg!("Tickets not refunded");
+        Err(PoolError::InvalidStatus.into())
+    }
+
     pub fn show_raffle(
         ctx : Context<ShowRaffle>,
         _is_show : bool,
@@ -308,6 +981,15 @@ pub mod raffle{
         Ok(())
     }
 
+    pub fn set_sol_fee(
+        ctx : Context<SetSolFee>,
+        _sol_fee_lamports : u64
+    ) -> ProgramResult {
+        let raffle_system = &mut ctx.accounts.raffle_system;
+        raffle_system.sol_fee_lamports = _sol_fee_lamports;
+        Ok(())
+    }
+
     pub fn claim(
         ctx : Context<Claim>,
         _amount : u64
@@ -318,14 +1000,22 @@ pub mod raffle{
             msg!("Invalid manager");
             return Err(PoolError::InvalidPoolOwner.into());
         }
+        if _amount > raffle_system.accrued_sol_fees {
+            msg!("Amount exceeds accrued fees");
+            return Err(PoolError::InsufficentFunds.into());
+        }
+        let rent_exempt = ctx.accounts.rent.minimum_balance(ctx.accounts.raffle_system_address.data_len());
+        if ctx.accounts.raffle_system_address.lamports().saturating_sub(rent_exempt) < _amount {
+            msg!("Amount exceeds lamports above rent exemption");
+            return Err(PoolError::InsufficentFunds.into());
+        }
 
         sol_transfer(
-            &mut ctx.accounts.raffle_system_address,
-            &mut ctx.accounts.owner,
+            &ctx.accounts.raffle_system_address,
+            &ctx.accounts.owner,
             _amount
         )?;
-
-        // raffle_system.pause_flag = false;
+        raffle_system.accrued_sol_fees -= _amount;
 
         Ok(())
     }
@@ -334,19 +1024,40 @@ pub mod raffle{
         ctx : Context<ClaimNft>,
         num : u32,
         ) -> ProgramResult {
+        if ctx.accounts.raffle_system.pause_flag {
+            msg!("Raffle system is paused");
+            return Err(PoolError::Paused.into());
+        }
         let raffle_system = &mut ctx.accounts.raffle_system;
         let raffle = &ctx.accounts.raffle;
-        if raffle.status != 2 {
-            return Err(PoolError::InvalidStatus.into());
-        }
+        raffle.check_status(&[RaffleStatus::Drawn])?;
         let spot = get_spot(&ctx.accounts.spot_store, num as usize)?;
-        if spot.claimed {
+        if spot.claimed != 0 {
             return Err(PoolError::AlreadyClaimed.into());
         }
         let wallet = get_user(&ctx.accounts.ledger, spot.winner_ticket as usize)?;
         if wallet != ctx.accounts.owner.key(){
             return Err(PoolError::NotMatch.into());
         }
+        if spot.nft != ctx.accounts.nft.key() || *ctx.accounts.nft_to.key != get_associated_token_address(&wallet, &spot.nft) {
+            return Err(PoolError::NotMatch.into());
+        }
+        if ctx.accounts.nft_to.data_is_empty() {
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.associated_token_program.to_account_info().clone(),
+                Create{
+                    payer : ctx.accounts.owner.to_account_info().clone(),
+                    associated_token : ctx.accounts.nft_to.clone(),
+                    authority : ctx.accounts.owner.to_account_info().clone(),
+                    mint : ctx.accounts.nft.to_account_info().clone(),
+                    system_program : ctx.accounts.system_program.to_account_info().clone(),
+                    token_program : ctx.accounts.token_program.to_account_info().clone(),
+                    rent : ctx.accounts.rent.to_account_info().clone(),
+                }
+            );
+            associated_token::create(cpi_ctx)?;
+        }
+        check_token_account(&ctx.accounts.nft_to, &wallet, &spot.nft)?;
 
         let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
         let signer = &[&raffle_system_seeds[..]];
@@ -361,20 +1072,41 @@ pub mod raffle{
             signer
         );
         token::transfer(cpi_ctx, 1)?;
+        if ctx.accounts.nft_from.amount == 1 {
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info().clone(),
+                CloseAccount{
+                    account : ctx.accounts.nft_from.to_account_info().clone(),
+                    destination : ctx.accounts.creator.clone(),
+                    authority : raffle_system.to_account_info().clone(),
+                },
+                signer
+            );
+            token::close_account(cpi_ctx)?;
+        }
 
         set_spot(&mut ctx.accounts.spot_store, num as usize, Spot{
             nft : spot.nft,
             winner_ticket : spot.winner_ticket,
-            claimed : true,
-        });
+            claimed : 1,
+        })?;
 
         Ok(())
     }
 
+    /// Withdraws proceeds a settled raffle paid into the system
+    /// `token_account`, up to what that raffle has left.
     pub fn redeem_token(
         ctx : Context<RedeemToken>,
         amount : u64,
         )->ProgramResult{
+        let raffle = &mut ctx.accounts.raffle;
+        raffle.check_status(&[RaffleStatus::Drawn])?;
+        if amount > raffle.settled_revenue {
+            msg!("Amount exceeds settled revenue");
+            return Err(PoolError::InsufficentFunds.into());
+        }
+        raffle.settled_revenue -= amount;
         let raffle_system = &mut ctx.accounts.raffle_system;
         let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
         let signer = &[&raffle_system_seeds[..]];
@@ -397,7 +1129,7 @@ struct SolTransferToPoolParams<'a> {
     /// CHECK:
     pub source: AccountInfo<'a>,
     /// CHECK:
-    pub destination: ProgramAccount<'a, RaffleSystem>,
+    pub destination: AccountInfo<'a>,
     /// CHECK:
     pub system: AccountInfo<'a>,
     /// CHECK:
@@ -415,40 +1147,185 @@ fn sol_transfer_to_pool(params: SolTransferToPoolParams<'_>) -> ProgramResult {
     let result = invoke(
         &anchor_lang::solana_program::system_instruction::transfer(
             source.key,
-            &destination.key(),
+            destination.key,
             amount,
         ),
-        &[source, destination.to_account_info(), system],
+        &[source, destination, system],
     );
 
     result.map_err(|_| PoolError::SolTransferFailed.into())
 }
 
-fn sol_transfer(
-    from_account: &AccountInfo,
-    to_account: &AccountInfo,
-    amount_of_lamports: u64,
-) -> ProgramResult {
-    // Does the from account have enough lamports to transfer?
-    if **from_account.try_borrow_lamports()? < amount_of_lamports {
-        msg!("Insufficent funds");
-        return Err(PoolError::InsufficentFunds.into());
-    }
-    // Debit from_account and credit to_account
-    **from_account.try_borrow_mut_lamports()? -= amount_of_lamports;
-    **to_account.try_borrow_mut_lamports()? += amount_of_lamports;
-    Ok(())
+struct CrankRewardParams<'a, 'info> {
+    pub raffle_system: &'a ProgramAccount<'info, RaffleSystem>,
+    pub raffle: &'a mut ProgramAccount<'info, Raffle>,
+    pub cranker: AccountInfo<'info>,
+    pub token_from: AccountInfo<'info>,
+    pub token_to: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
 }
 
-#[derive(Accounts)]
-pub struct RedeemToken<'info>{
-    #[account(mut)]
-    owner : Signer<'info>,
-
-    #[account(mut, has_one=owner)]
-    raffle_system : ProgramAccount<'info,RaffleSystem>,
+/// Pays the raffle's crank reward, capped by what it holds, to whoever
+/// drew its winners. SPL rewards go to a token account of the cranker.
+fn pay_crank_reward(params: CrankRewardParams<'_, '_>) -> ProgramResult {
+    let CrankRewardParams {
+        raffle_system,
+        raffle,
+        cranker,
+        token_from,
+        token_to,
+        token_program
+    } = params;
 
-    #[account(mut, address=raffle_system.token_account)]
+    if raffle.crank_reward == 0 {
+        return Ok(());
+    }
+    if raffle.payment_mint == native_mint::ID {
+        let reward = raffle.crank_reward.min(raffle.sol_balance);
+        sol_transfer(&raffle.to_account_info(), &cranker, reward)?;
+        raffle.sol_balance -= reward;
+        return Ok(());
+    }
+    check_token_account(&token_to, cranker.key, &raffle.payment_mint)?;
+    let reward = raffle.crank_reward.min(Account::<TokenAccount>::try_from(&token_from)?.amount);
+    let raffle_system_seeds = &[raffle_system.rand.as_ref(),&[raffle_system.bump]];
+    let signer = &[&raffle_system_seeds[..]];
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program,
+        Transfer{
+            from : token_from,
+            to : token_to,
+            authority : raffle_system.to_account_info(),
+        },
+        signer
+    );
+    token::transfer(cpi_ctx, reward)
+}
+
+struct CreateStoreParams<'a, 'b> {
+    pub payer: AccountInfo<'a>,
+    pub store: AccountInfo<'a>,
+    pub system: AccountInfo<'a>,
+    pub lamports: u64,
+    pub seeds: &'b [&'b [u8]],
+    pub size: usize,
+    pub owner: Pubkey,
+}
+
+/// Creates a ledger, spot store or payment escrow at its PDA, owned by
+/// `owner`.
+/// Accounts created through a CPI are capped at
+/// MAX_PERMITTED_DATA_INCREASE bytes, so larger sizes fail up front.
+fn create_store(params: CreateStoreParams<'_, '_>) -> ProgramResult {
+    let CreateStoreParams {
+        payer,
+        store,
+        system,
+        lamports,
+        seeds,
+        size,
+        owner
+    } = params;
+
+    if size > MAX_PERMITTED_DATA_INCREASE {
+        msg!("Ledger or spot store too large");
+        return Err(PoolError::StoreTooLarge.into());
+    }
+    let address = Pubkey::create_program_address(seeds, &ID)
+        .map_err(|_| PoolError::InvalidStoreAccount)?;
+    if address != *store.key {
+        return Err(PoolError::InvalidStoreAccount.into());
+    }
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            store.key,
+            lamports,
+            size as u64,
+            &owner,
+        ),
+        &[payer, store, system],
+        &[seeds],
+    )
+}
+
+/// Sets the data length of a ledger or spot store. Solana 1.7 has no
+/// `AccountInfo::realloc`, so this does what it does later on: the loader
+/// keeps the serialized length in the 8 bytes before the data and reserves
+/// MAX_PERMITTED_DATA_INCREASE spare bytes after it, which bounds the
+/// growth of an account to one call per instruction. Only accounts passed
+/// in by the loader have that layout, and only loaders with realloc
+/// support keep the new length.
+fn resize_store(
+    a : &AccountInfo,
+    new_size : usize,
+    ) -> ProgramResult {
+    let current_size = a.data_len();
+    if new_size > current_size + MAX_PERMITTED_DATA_INCREASE {
+        msg!("Ledger or spot store grows too much at once");
+        return Err(PoolError::StoreTooLarge.into());
+    }
+    let mut data = a.try_borrow_mut_data()?;
+    unsafe {
+        let data_ptr = data.as_mut_ptr();
+        let len_ptr = data_ptr.offset(-8) as *mut u64;
+        if *len_ptr != current_size as u64 {
+            msg!("Account data is not laid out by the loader");
+            return Err(PoolError::InvalidStoreAccount.into());
+        }
+        *len_ptr = new_size as u64;
+        *data = std::slice::from_raw_parts_mut(data_ptr, new_size);
+    }
+    if new_size > current_size {
+        data[current_size..].fill(0);
+    }
+    Ok(())
+}
+
+/// Closes a ledger or spot store, moving its lamports to `destination`.
+/// The data is zeroed so the account no longer passes `is_raffle_store`.
+fn close_store(
+    a : &AccountInfo,
+    destination : &AccountInfo,
+    ) -> ProgramResult {
+    let lamports = a.lamports();
+    **destination.try_borrow_mut_lamports()? = destination.lamports().checked_add(lamports).ok_or(PoolError::NumericalOverflowError)?;
+    **a.try_borrow_mut_lamports()? = 0;
+    a.try_borrow_mut_data()?.fill(0);
+    Ok(())
+}
+
+fn sol_transfer(
+    from_account: &AccountInfo,
+    to_account: &AccountInfo,
+    amount_of_lamports: u64,
+) -> ProgramResult {
+    // Does the from account have enough lamports to transfer?
+    if **from_account.try_borrow_lamports()? < amount_of_lamports {
+        msg!("Insufficent funds");
+        return Err(PoolError::InsufficentFunds.into());
+    }
+    // Debit from_account and credit to_account
+    **from_account.try_borrow_mut_lamports()? -= amount_of_lamports;
+    **to_account.try_borrow_mut_lamports()? += amount_of_lamports;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct RedeemToken<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut)]
+    raffle_system : ProgramAccount<'info,RaffleSystem>,
+
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_TREASURY))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, address=raffle_system.token_account)]
     token_from : Account<'info, TokenAccount>,
 
     #[account(mut)]
@@ -466,39 +1343,141 @@ pub struct ClaimNft<'info>{
     #[account(mut)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
-    #[account(mut)]
+    #[account(mut, has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 
-    #[account(mut)]
+    #[account(mut, address=raffle.creator)]
+    creator : AccountInfo<'info>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
     spot_store : AccountInfo<'info>,
 
+    #[account(constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
     ledger : AccountInfo<'info>,
 
-    #[account(mut)]
+    nft : Account<'info, Mint>,
+
+    #[account(mut, constraint= nft_from.owner==raffle_system.key() && nft_from.mint==nft.key())]
     nft_from : Account<'info, TokenAccount>,
 
-    #[account(mut)]
-    nft_to : Account<'info, TokenAccount>,
+    /// Winner's associated token account for `nft`, created if missing.
+    #[account(mut, constraint= *nft_to.key==get_associated_token_address(&owner.key(), &nft.key()))]
+    nft_to : AccountInfo<'info>,
 
-    token_program : Program<'info, Token>
+    token_program : Program<'info, Token>,
+
+    associated_token_program : Program<'info, AssociatedToken>,
+
+    system_program : Program<'info, System>,
+
+    rent : Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 pub struct EndRaffle<'info>{
+    #[account(mut)]
+    cranker : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
+    spot_store : AccountInfo<'info>,
+
+    #[account(constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
+    ledger : AccountInfo<'info>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_from.key==raffle.escrow_account)]
+    token_from : AccountInfo<'info>,
+
+    /// Recipient token account, unused by SOL raffles.
+    #[account(mut)]
+    token_to : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>,
+
+    clock : AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+pub struct RevealRaffle<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(constraint= raffle.creator==owner.key() || has_permission(&raffle_system, &owner.key(), &role, PERMISSION_DRAW))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
+    spot_store : AccountInfo<'info>,
+
+    #[account(constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
+    ledger : AccountInfo<'info>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_from.key==raffle.escrow_account)]
+    token_from : AccountInfo<'info>,
+
+    /// Crank reward recipient token account, unused by SOL raffles.
+    #[account(mut)]
+    token_to : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>,
+
+    #[account(address=sysvar::slot_hashes::ID)]
+    slot_hashes : AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+pub struct RequestRandomness<'info>{
+    #[account(mut)]
+    cranker : Signer<'info>,
+
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
     #[account(mut, has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 
+    oracle : AccountInfo<'info>,
+
+    clock : AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+pub struct ConsumeRandomness<'info>{
     #[account(mut)]
+    cranker : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system, has_one=oracle)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
     spot_store : AccountInfo<'info>,
 
+    #[account(constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
     ledger : AccountInfo<'info>,
 
+    oracle : AccountInfo<'info>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_from.key==raffle.escrow_account)]
+    token_from : AccountInfo<'info>,
+
+    /// Crank reward recipient token account, unused by SOL raffles.
+    #[account(mut)]
+    token_to : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>,
+
     clock : AccountInfo<'info>
 }
 
@@ -513,9 +1492,10 @@ pub struct EndState<'info>{
     #[account(mut, has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 
-    #[account(mut)]
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
     spot_store : AccountInfo<'info>,
 
+    #[account(constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
     ledger : AccountInfo<'info>,
 }
 
@@ -527,20 +1507,21 @@ pub struct BuyTicket<'info>{
     #[account(mut)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
-    #[account(mut)]
+    #[account(mut, has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 
     #[account(mut, constraint= user_data.owner==owner.key() && user_data.raffle==raffle.key())]
     user_data : ProgramAccount<'info, UserData>,
 
-    #[account(mut)]
+    #[account(mut, constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
     ledger : AccountInfo<'info>,
 
-    #[account(mut, constraint= token_from.owner==owner.key() && token_from.mint==raffle_system.token_mint)]
-    token_from : Account<'info, TokenAccount>,
+    /// Buyer's token account and the payment escrow, unused by SOL raffles.
+    #[account(mut)]
+    token_from : AccountInfo<'info>,
 
-    #[account(mut, address=raffle_system.token_account)]
-    token_to : Account<'info, TokenAccount>,
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_to.key==raffle.escrow_account)]
+    token_to : AccountInfo<'info>,
 
     token_program : Program<'info, Token>,
 
@@ -549,14 +1530,138 @@ pub struct BuyTicket<'info>{
     clock : AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct GrowLedger<'info>{
+    #[account(mut)]
+    payer : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
+    ledger : AccountInfo<'info>,
+
+    system_program : Program<'info, System>,
+
+    rent : Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CancelRaffle<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system, constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
+    raffle : ProgramAccount<'info, Raffle>,
+}
+
+#[derive(Accounts)]
+pub struct RefundTickets<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, constraint= user_data.owner==owner.key() && user_data.raffle==raffle.key())]
+    user_data : ProgramAccount<'info, UserData>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_from.key==raffle.escrow_account)]
+    token_from : AccountInfo<'info>,
+
+    /// Recipient token account, unused by SOL raffles.
+    #[account(mut)]
+    token_to : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>
+}
+
+#[derive(Accounts)]
+pub struct SettleRaffle<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_TREASURY))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system)]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *token_from.key==raffle.escrow_account)]
+    token_from : AccountInfo<'info>,
+
+    #[account(mut)]
+    token_to : AccountInfo<'info>,
+
+    #[account(mut)]
+    fee_to : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>
+}
+
+#[derive(Accounts)]
+pub struct CloseRaffle<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system, close=creator, constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
+    raffle : ProgramAccount<'info, Raffle>,
+
+    #[account(mut, address=raffle.creator)]
+    creator : AccountInfo<'info>,
+
+    #[account(mut, constraint= raffle.ledger_account==(*ledger.key) && is_raffle_store(&ledger, Ledger::discriminator(), &raffle.key()))]
+    ledger : AccountInfo<'info>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
+    spot_store : AccountInfo<'info>,
+
+    /// Payment escrow, unused by SOL raffles.
+    #[account(mut, constraint= raffle.payment_mint==native_mint::ID || *escrow_account.key==raffle.escrow_account)]
+    escrow_account : AccountInfo<'info>,
+
+    token_program : Program<'info, Token>
+}
+
+#[derive(Accounts)]
+pub struct CloseUserData<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut, close=owner, constraint= user_data.owner==owner.key() && user_data.raffle==raffle.key())]
+    user_data : ProgramAccount<'info, UserData>,
+
+    raffle : AccountInfo<'info>,
+}
+
 #[derive(Accounts)]
 pub struct ShowRaffle<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_MODERATE))]
+    role : AccountInfo<'info>,
+
     #[account(mut, has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 }
@@ -566,10 +1671,23 @@ pub struct UpdateRaffle<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
-    #[account(mut, has_one=raffle_system)]
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system, constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
+    raffle : ProgramAccount<'info, Raffle>,
+}
+
+#[derive(Accounts)]
+pub struct SetTreasuryProceeds<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system, constraint= raffle.creator==owner.key())]
     raffle : ProgramAccount<'info, Raffle>,
 }
 
@@ -578,10 +1696,12 @@ pub struct StartRaffle<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
-    #[account(mut, has_one=raffle_system)]
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
+    #[account(mut, has_one=raffle_system, constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
     raffle : ProgramAccount<'info, Raffle>,
 
     clock : AccountInfo<'info>
@@ -593,17 +1713,21 @@ pub struct RedeemSpot<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
     #[account(mut,
         has_one=raffle_system,
-        constraint= raffle.status==0
-            && raffle.spots_account==(*spot_store.key)
-            && raffle.spot_num>_index)]
+        constraint= raffle.spot_num>_index,
+        constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
     raffle : ProgramAccount<'info, Raffle>,
 
-    #[account(mut)]
+    #[account(mut, address=raffle.creator)]
+    creator : AccountInfo<'info>,
+
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
     spot_store : AccountInfo<'info>,
 
     #[account(mut)]
@@ -612,7 +1736,7 @@ pub struct RedeemSpot<'info>{
     #[account(mut, constraint= nft_from.owner==raffle_system.key() && nft_from.mint==nft.key())]
     nft_from : Account<'info, TokenAccount>,
 
-    #[account(mut, constraint= nft_to.owner==owner.key() && nft_to.mint==nft.key())]
+    #[account(mut, constraint= nft_to.owner==raffle.creator && nft_to.mint==nft.key())]
     nft_to : Account<'info, TokenAccount>,    
 
     token_program : Program<'info, Token>
@@ -624,17 +1748,18 @@ pub struct PutSpot<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
+
     #[account(mut,
         has_one=raffle_system,
-        constraint= raffle.status==0
-            && raffle.spots_account==(*spot_store.key)
-            && raffle.spot_num>_index)]
+        constraint= raffle.spot_num>_index,
+        constraint= raffle.creator==owner.key() || raffle_system.owner==owner.key())]
     raffle : ProgramAccount<'info, Raffle>,
 
-    #[account(mut)]
+    #[account(mut, constraint= raffle.spots_account==(*spot_store.key) && is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle.key()))]
     spot_store : AccountInfo<'info>,
 
     #[account(mut)]
@@ -655,6 +1780,9 @@ pub struct InitUserData<'info>{
     #[account(mut)]
     owner : Signer<'info>,
 
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(has_one=raffle_system)]
     raffle : ProgramAccount<'info, Raffle>,
 
     #[account(init, payer=owner, space=8+USERDATA_SIZE, seeds=[owner.key().as_ref(), raffle.key().as_ref()], bump=_bump)]
@@ -668,8 +1796,10 @@ pub struct InitRaffle<'info>{
     #[account(mut)]
     owner : Signer<'info>,
     
-    #[account(has_one=owner)]
     raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(constraint= has_permission(&raffle_system, &owner.key(), &role, PERMISSION_CREATE_RAFFLE))]
+    role : AccountInfo<'info>,
     
     #[account(init, payer=owner, space=8+RAFFLE_SIZE)]
     raffle : ProgramAccount<'info, Raffle>,
@@ -680,10 +1810,21 @@ pub struct InitRaffle<'info>{
     #[account(mut)]
     spot_store : AccountInfo<'info>,
 
-    system_program : Program<'info, System>
-}
+    payment_mint : Account<'info, Mint>,
 
-#[derive(Accounts)]
+    /// Payment escrow PDA, only created when `payment_mint` is not the
+    /// native mint. SOL raffles keep their proceeds in the raffle account.
+    #[account(mut)]
+    escrow_account : AccountInfo<'info>,
+
+    system_program : Program<'info, System>,
+
+    token_program : Program<'info, Token>,
+
+    rent : Sysvar<'info, Rent>
+}
+
+#[derive(Accounts)]
 pub struct TransferAuthority<'info>{
     #[account(mut)]
     owner : Signer<'info>,
@@ -692,6 +1833,74 @@ pub struct TransferAuthority<'info>{
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 }
 
+#[derive(Accounts)]
+pub struct AcceptOwner<'info>{
+    #[account(mut)]
+    new_owner : Signer<'info>,
+
+    #[account(mut, constraint= raffle_system.pending_owner==new_owner.key())]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitRole<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    member : AccountInfo<'info>,
+
+    #[account(init, payer=owner, space=8+ROLE_SIZE, seeds=[raffle_system.key().as_ref(), member.key.as_ref(), ROLE_SEED], bump=_bump)]
+    role : ProgramAccount<'info, Role>,
+
+    system_program : Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct SetRole<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system)]
+    role : ProgramAccount<'info, Role>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveRole<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+
+    #[account(mut, has_one=raffle_system, close=owner)]
+    role : ProgramAccount<'info, Role>,
+}
+
+#[derive(Accounts)]
+pub struct SetOracleProgram<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+}
+
+#[derive(Accounts)]
+pub struct SetFee<'info>{
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,has_one=owner)]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+}
+
 #[derive(Accounts)]
 pub struct SetManager<'info>{
     #[account(mut)]
@@ -701,6 +1910,15 @@ pub struct SetManager<'info>{
     raffle_system : ProgramAccount<'info, RaffleSystem>,
 }
 
+#[derive(Accounts)]
+pub struct AcceptManager<'info>{
+    #[account(mut)]
+    new_manager : Signer<'info>,
+
+    #[account(mut, constraint= raffle_system.pending_manager==new_manager.key())]
+    raffle_system : ProgramAccount<'info, RaffleSystem>,
+}
+
 #[derive(Accounts)]
 pub struct Pause<'info> {
     /// CHECK:
@@ -712,6 +1930,15 @@ pub struct Pause<'info> {
     raffle_system : ProgramAccount<'info,RaffleSystem>   
 }
 
+#[derive(Accounts)]
+pub struct SetSolFee<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut, constraint= owner.key()==raffle_system.manager)]
+    raffle_system : ProgramAccount<'info,RaffleSystem>
+}
+
 #[derive(Accounts)]
 pub struct Claim<'info> {
     /// CHECK:
@@ -723,8 +1950,10 @@ pub struct Claim<'info> {
     raffle_system : ProgramAccount<'info,RaffleSystem>,
 
     /// CHECK:
-    #[account(mut)]
-    raffle_system_address : AccountInfo<'info>
+    #[account(mut, address=raffle_system.key())]
+    raffle_system_address : AccountInfo<'info>,
+
+    rent : Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
@@ -750,16 +1979,43 @@ pub struct InitRaffleSystem<'info>{
     system_program : Program<'info, System>
 }
 
-pub const RAFFLE_SYSTEM_SIZE : usize = 32*5+1+1;
+pub const RAFFLE_SYSTEM_SIZE : usize = 32*10+1+1+2+8+8;
 pub const MAX_ROOM_NAME_SIZE : usize = 50;
 pub const LOGO_SIZE : usize = 200;
 pub const DISCORD_SIZE : usize = 100;
 pub const TWITTER_SIZE : usize = 100;
-pub const RAFFLE_SIZE : usize = 32+MAX_ROOM_NAME_SIZE+LOGO_SIZE+DISCORD_SIZE+TWITTER_SIZE+1+8+4+4+8+8+32+32+1+4+96;
-pub const SPOT_SIZE : usize = 32 + 4 + 1;
-pub const USERDATA_SIZE : usize = 32+32+4+1;
+pub const RAFFLE_SIZE : usize = 32+MAX_ROOM_NAME_SIZE+LOGO_SIZE+DISCORD_SIZE+TWITTER_SIZE+1+8+4+4+8+8+32+32+1+4+32+32+8+1+1+4+32+32+8+32+1+8+8+8+1+96;
+pub const ESCROW_SEED : &[u8] = b"escrow";
+pub const LEDGER_SEED : &[u8] = b"ledger";
+pub const SPOT_STORE_SEED : &[u8] = b"spots";
+pub const LEDGER_HEADER_SIZE : usize = 8+32+4+4;
+pub const SPOT_STORE_HEADER_SIZE : usize = 8+32+4;
+pub const MAX_FEE_BPS : u16 = 1000;
+pub const FEE_BPS_DENOMINATOR : u64 = 10000;
+/// Slots after the oracle writes its result in which it can be consumed.
+/// A raffle gets a single request, so a missed result fails the raffle.
+pub const ORACLE_MAX_AGE : u64 = 150;
+/// Seconds after the end of its period in which an oracle raffle must be
+/// drawn before `end_raffle` fails it.
+pub const DRAW_TIMEOUT : u64 = 3*24*60*60;
+
+pub const SELECTION_INDEPENDENT : u8 = 0;
+pub const SELECTION_DISTINCT_TICKET : u8 = 1;
+pub const SELECTION_DISTINCT_WALLET : u8 = 2;
+/// Winner ticket of a spot that could not be awarded a distinct winner.
+pub const NO_WINNER : u32 = u32::MAX;
+pub const USERDATA_SIZE : usize = 32+32+4+1+1;
+pub const ROLE_SEED : &[u8] = b"role";
+pub const ROLE_SIZE : usize = 32+32+4+1;
+
+/// Role permissions. The system owner implicitly holds all of them.
+pub const PERMISSION_CREATE_RAFFLE : u32 = 1;
+pub const PERMISSION_DRAW : u32 = 1 << 1;
+pub const PERMISSION_MODERATE : u32 = 1 << 2;
+pub const PERMISSION_TREASURY : u32 = 1 << 3;
 
 #[account]
+#[derive(Default)]
 pub struct RaffleSystem{
     owner : Pubkey,
     manager : Pubkey,
@@ -768,16 +2024,28 @@ pub struct RaffleSystem{
     token_mint : Pubkey,
     token_account : Pubkey,
     bump : u8,
+    oracle_program : Pubkey,
+    fee_bps : u16,
+    fee_recipient : Pubkey,
+    sol_fee_lamports : u64,
+    /// Ticket fees collected in the PDA and not yet claimed.
+    accrued_sol_fees : u64,
+    /// Proposed owner and manager, or the default key when none.
+    pending_owner : Pubkey,
+    pending_manager : Pubkey,
+    /// Trusted result account of `oracle_program`.
+    oracle_account : Pubkey,
 }
 
 #[account]
+#[derive(Default)]
 pub struct Raffle{
     raffle_system : Pubkey,
     room_name : String,
     logo : String,
     discord : String,
     twitter : String,
-    status : u8,
+    status : RaffleStatus,
     ticket_value : u64,
     spot_num : u32,
     max_ticket_num : u32,
@@ -787,6 +2055,95 @@ pub struct Raffle{
     spots_account : Pubkey,
     is_show : bool,
     max_ticket_per_user : u32,
+    commitment : [u8;32],
+    oracle : Pubkey,
+    oracle_request_slot : u64,
+    selection_mode : u8,
+    manual_award : bool,
+    min_ticket_num : u32,
+    escrow_account : Pubkey,
+    payment_mint : Pubkey,
+    sol_balance : u64,
+    creator : Pubkey,
+    allow_early_end : bool,
+    crank_reward : u64,
+    /// Proceeds settled into the system `token_account` that the owner can
+    /// still withdraw with `redeem_token`.
+    settled_revenue : u64,
+    /// Slot at which `end_raffle` closed the sale of a commit-reveal raffle.
+    /// The reveal must use this slot's hash.
+    reveal_slot : u64,
+    /// Set by the creator to settle proceeds into the system `token_account`.
+    treasury_proceeds : bool,
+}
+
+impl Raffle{
+    /// Fails with `InvalidStatus` unless the raffle is in one of `allowed`.
+    pub fn check_status(
+        &self,
+        allowed : &[RaffleStatus],
+        ) -> ProgramResult {
+        if !allowed.contains(&self.status) {
+            msg!("Invalid status");
+            return Err(PoolError::InvalidStatus.into());
+        }
+        Ok(())
+    }
+
+    /// Moves the raffle to `to`. Every status change goes through here so
+    /// the allowed edges live in `RaffleStatus::can_transition` only.
+    pub fn transition(
+        &mut self,
+        to : RaffleStatus,
+        ) -> ProgramResult {
+        if !self.status.can_transition(to) {
+            msg!("Invalid status");
+            return Err(PoolError::InvalidStatus.into());
+        }
+        self.status = to;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RaffleStatus{
+    Pending,
+    Open,
+    Drawn,
+    Cancelled,
+    Failed,
+}
+
+// Written out because `#[default]` on variants is newer than the BPF
+// toolchain this program builds with.
+#[allow(clippy::derivable_impls)]
+impl Default for RaffleStatus{
+    fn default() -> Self {
+        RaffleStatus::Pending
+    }
+}
+
+impl RaffleStatus{
+    pub fn can_transition(
+        self,
+        to : RaffleStatus,
+        ) -> bool {
+        use RaffleStatus::*;
+        matches!((self, to),
+            (Pending, Open)
+            | (Pending, Cancelled)
+            | (Open, Pending)
+            | (Open, Drawn)
+            | (Open, Failed)
+            | (Open, Cancelled))
+    }
+}
+
+#[event]
+pub struct ManualAwardEvent{
+    pub raffle : Pubkey,
+    pub manager : Pubkey,
+    pub winner_ticket : u32,
 }
 
 #[account]
@@ -794,122 +2151,430 @@ pub struct UserData{
     owner : Pubkey,
     raffle : Pubkey,
     ticket_num : u32,
-    bump : u8
+    bump : u8,
+    refunded : bool,
 }
 
+/// Permissions the owner granted `member`, at the PDA
+/// `[raffle_system, member, ROLE_SEED]`.
 #[account]
-#[derive(Default)]
+pub struct Role{
+    raffle_system : Pubkey,
+    member : Pubkey,
+    permissions : u32,
+    bump : u8,
+}
+
+/// Ledger header, followed by `range_num` `TicketRange`s sorted by `end`.
+/// `count` is the total number of tickets sold.
+#[account(zero_copy)]
+#[allow(clippy::repr_packed_without_abi)]
 pub struct Ledger{
     pub raffle_account : Pubkey,
-    pub users : Vec<Pubkey>
+    pub count : u32,
+    pub range_num : u32,
+}
+
+/// Tickets `[previous end, end)` belong to `buyer`.
+#[zero_copy]
+#[allow(clippy::repr_packed_without_abi)]
+pub struct TicketRange{
+    pub buyer : Pubkey,
+    pub end : u32,
+}
+
+unsafe impl Pod for TicketRange {}
+unsafe impl Zeroable for TicketRange {}
+
+/// Spot store header. Spot `i` is the `i`-th `Spot` stored after it.
+#[account(zero_copy)]
+#[allow(clippy::repr_packed_without_abi)]
+pub struct SpotStore{
+    pub raffle_account : Pubkey,
+    pub spot_num : u32,
+}
+
+#[zero_copy]
+#[allow(clippy::repr_packed_without_abi)]
+pub struct Spot{
+    pub nft : Pubkey,
+    pub winner_ticket : u32,
+    pub claimed : u8,
+}
+
+unsafe impl Pod for Spot {}
+unsafe impl Zeroable for Spot {}
+
+/// Ranges a new ledger has room for: one per ticket at most, capped by
+/// what a single `create_account` CPI can allocate. `grow_ledger` adds
+/// more.
+pub fn ledger_capacity(
+    max_ticket_num : u32,
+    ) -> u32 {
+    let max_ranges = (MAX_PERMITTED_DATA_INCREASE - LEDGER_HEADER_SIZE) / size_of::<TicketRange>();
+    (max_ticket_num as usize).min(max_ranges) as u32
+}
+
+pub fn ledger_size(
+    range_num : u32,
+    ) -> usize {
+    LEDGER_HEADER_SIZE + range_num as usize * size_of::<TicketRange>()
+}
+
+pub fn spot_store_size(
+    spot_num : u32,
+    ) -> usize {
+    SPOT_STORE_HEADER_SIZE + spot_num as usize * size_of::<Spot>()
+}
+
+fn load_header<T : Pod>(
+    data : &[u8]
+    ) -> core::result::Result<&T, ProgramError> {
+    data.get(8..8+size_of::<T>())
+        .map(bytemuck::from_bytes)
+        .ok_or_else(|| PoolError::InvalidStoreAccount.into())
+}
+
+fn load_header_mut<T : Pod>(
+    data : &mut [u8]
+    ) -> core::result::Result<&mut T, ProgramError> {
+    data.get_mut(8..8+size_of::<T>())
+        .map(bytemuck::from_bytes_mut)
+        .ok_or_else(|| PoolError::InvalidStoreAccount.into())
+}
+
+fn load_entries<H : Pod, T : Pod>(
+    data : &[u8]
+    ) -> &[T] {
+    let body = data.get(8+size_of::<H>()..).unwrap_or(&[]);
+    bytemuck::cast_slice(&body[..body.len() / size_of::<T>() * size_of::<T>()])
+}
+
+fn load_entries_mut<H : Pod, T : Pod>(
+    data : &mut [u8]
+    ) -> &mut [T] {
+    let body = data.get_mut(8+size_of::<H>()..).unwrap_or(&mut []);
+    let len = body.len() / size_of::<T>() * size_of::<T>();
+    bytemuck::cast_slice_mut(&mut body[..len])
 }
 
 pub fn get_num(
-    data : &Ref<&mut [u8]>
+    a : &AccountInfo
     )-> core::result::Result<usize, ProgramError>{
-    return Ok(u32::from_le_bytes(*array_ref![data,40,4]) as usize);
+    Ok(load_header::<Ledger>(&a.data.borrow())?.count as usize)
 }
 
+/// Buyer of ticket `index`, found by binary search over the ranges.
 pub fn get_user(
     a : &AccountInfo,
     index : usize,
     ) -> core::result::Result<Pubkey, ProgramError> {
-    let arr = a.data.borrow();
-    let total = get_num(&arr)?;
-    if index > total{
+    let data = a.data.borrow();
+    let ledger = load_header::<Ledger>(&data)?;
+    if index >= ledger.count as usize {
         return Err(PoolError::IndexGreaterThanLength.into());
     }
-    let data_array = &arr[44+index*32..44+index*32+32];
-    let user : Pubkey = Pubkey::try_from_slice(data_array)?;
-    Ok(user)
+    let ranges = load_entries::<Ledger, TicketRange>(&data)
+        .get(..ledger.range_num as usize)
+        .ok_or(PoolError::InvalidStoreAccount)?;
+    let position = ranges.partition_point(|range| range.end as usize <= index);
+    let range = ranges.get(position).ok_or(PoolError::IndexGreaterThanLength)?;
+    Ok(range.buyer)
 }
 
-pub fn set_user(
+/// Records `num` more tickets for `user`. A purchase right after the
+/// same buyer's previous one extends that range instead of adding one.
+pub fn push_tickets(
     a : &mut AccountInfo,
-    index : usize,
     user : Pubkey,
     num : u32,
-    ){
-    let mut arr = a.data.borrow_mut();
-    let data_array = user.try_to_vec().unwrap();
-    let vec_start = 44+index*32;
-    let length = 32 * num as usize;
-    for i in 0..length{
-        arr[vec_start+i] = data_array[i%32]
+    ) -> ProgramResult {
+    if num == 0 {
+        return Ok(());
     }
-}
-
-pub fn set_count(
-    a : &AccountInfo,
-    count : u32
-    ){
-    let mut arr = a.data.borrow_mut();
-    let data_array = count.try_to_vec().unwrap();
-    let vec_start = 40;
-    for i in 0..data_array.len(){
-        arr[vec_start+i] = data_array[i]
+    let mut data = a.data.borrow_mut();
+    let ledger = *load_header::<Ledger>(&data)?;
+    let count = ledger.count.checked_add(num).ok_or(PoolError::Overflow)?;
+    let range_num = ledger.range_num as usize;
+    let ranges = load_entries_mut::<Ledger, TicketRange>(&mut data);
+    match range_num.checked_sub(1).and_then(|last| ranges.get_mut(last)) {
+        Some(last) if last.buyer == user => last.end = count,
+        _ => {
+            *ranges.get_mut(range_num).ok_or(PoolError::LedgerFull)? = TicketRange{
+                buyer : user,
+                end : count,
+            };
+            load_header_mut::<Ledger>(&mut data)?.range_num += 1;
+        }
     }
-}
-
-#[account]
-#[derive(Default)]
-pub struct SpotStore{
-    pub raffle_account : Pubkey,
-    pub spots : Vec<Spot>
-}
-
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
-pub struct Spot{
-    pub nft : Pubkey,
-    pub winner_ticket : u32,
-    pub claimed : bool,
+    load_header_mut::<Ledger>(&mut data)?.count = count;
+    Ok(())
 }
 
 pub fn set_spot(
     a : &mut AccountInfo,
     index : usize,
     spot : Spot,
-    ){
-    let mut arr = a.data.borrow_mut();
-    let data_array = spot.try_to_vec().unwrap();
-    let vec_start = 44+index*SPOT_SIZE;
-    for i in 0..SPOT_SIZE{
-        arr[vec_start+i] = data_array[i];
-    }
+    ) -> ProgramResult {
+    let mut data = a.data.borrow_mut();
+    *load_entries_mut::<SpotStore, Spot>(&mut data).get_mut(index).ok_or(PoolError::InvalidIndex)? = spot;
+    Ok(())
 }
 
 pub fn set_winner(
     a : &mut AccountInfo,
     winners : Vec<u32>
-    ){
-    let mut arr = a.data.borrow_mut();
-    let mut start = 44;
-    for i in 0..winners.len(){
-        let data_array = winners[i].try_to_vec().unwrap();
-        for i in 0..4{
-            arr[start+32+i] = data_array[i];
-        }
-        start+=SPOT_SIZE;
+    ) -> ProgramResult {
+    let mut data = a.data.borrow_mut();
+    let spots = load_entries_mut::<SpotStore, Spot>(&mut data);
+    if winners.len() > spots.len() {
+        return Err(PoolError::InvalidIndex.into());
+    }
+    for (spot, winner) in spots.iter_mut().zip(winners.iter()){
+        spot.winner_ticket = *winner;
     }
+    Ok(())
 }
 
+/// Raffle a ledger or spot store was created for, read through the
+/// header type its discriminator names.
 pub fn get_raffle_address(
     a : &AccountInfo
     ) -> core::result::Result<Pubkey, ProgramError> {
+    let data = a.data.borrow();
+    let discriminator = data.get(..8).ok_or(PoolError::InvalidStoreAccount)?;
+    if discriminator == Ledger::discriminator() {
+        Ok(load_header::<Ledger>(&data)?.raffle_account)
+    } else if discriminator == SpotStore::discriminator() {
+        Ok(load_header::<SpotStore>(&data)?.raffle_account)
+    } else {
+        Err(PoolError::InvalidStoreAccount.into())
+    }
+}
+
+/// Hash of `slot` from the SlotHashes sysvar, read in place to avoid
+/// deserializing all 512 entries. Fails before the slot is produced and
+/// once it has aged out.
+pub fn get_slot_hash(
+    a : &AccountInfo,
+    slot : u64,
+    ) -> core::result::Result<[u8;32], ProgramError> {
+    let arr = a.data.borrow();
+    if arr.len() < 8 {
+        return Err(PoolError::InvalidSlotHashes.into());
+    }
+    let len = (u64::from_le_bytes(*array_ref![arr,0,8]) as usize).min((arr.len() - 8) / 40);
+    for i in 0..len {
+        let entry = array_ref![arr,8+i*40,40];
+        if u64::from_le_bytes(*array_ref![entry,0,8]) == slot {
+            return Ok(*array_ref![entry,8,32]);
+        }
+    }
+    Err(PoolError::InvalidSlotHashes.into())
+}
+
+/// Draw seed of a commit-reveal raffle.
+pub fn reveal_seed(
+    secret : &[u8;32],
+    slot_hash : &[u8;32],
+    ) -> [u8;32] {
+    hashv(&[secret, slot_hash]).to_bytes()
+}
+
+/// Slot and result of an oracle account laid out as
+/// [discriminator][authority][slot u64][result 32 bytes].
+pub fn get_oracle_result(
+    a : &AccountInfo
+    ) -> core::result::Result<(u64, [u8;32]), ProgramError> {
     let arr = a.data.borrow();
-    let data_array = &arr[8..40];
-    let raffle : Pubkey = Pubkey::try_from_slice(data_array)?;
-    Ok(raffle)
+    if arr.len() < 80 {
+        return Err(PoolError::InvalidOracle.into());
+    }
+    Ok((u64::from_le_bytes(*array_ref![arr,40,8]), *array_ref![arr,48,32]))
+}
+
+fn draw_random(
+    seed : &[u8;32],
+    step : u32,
+    ) -> u64 {
+    let rand = hashv(&[seed, &step.to_le_bytes()]).to_bytes();
+    u64::from_le_bytes(*array_ref![rand,0,8])
+}
+
+/// Winner ticket index for every spot, derived only from the seed so
+/// anyone can recompute the draw.
+///
+/// Distinct tickets come from a partial Fisher-Yates shuffle over the
+/// ticket indices, keeping only the swapped positions in memory. Distinct
+/// wallets are drawn by ticket weight over the ledger ranges, skipping the
+/// ranges of wallets already picked, so each spot costs one pass over the
+/// ranges. Spots left over once the tickets (or wallets) run out get
+/// `NO_WINNER`.
+pub fn draw_winners(
+    seed : &[u8;32],
+    ledger : &AccountInfo,
+    ticket_num : u32,
+    spot_num : u32,
+    selection_mode : u8,
+    ) -> core::result::Result<Vec<u32>, ProgramError> {
+    let mut winners : Vec<u32> = vec![];
+    if selection_mode == SELECTION_INDEPENDENT {
+        for i in 0..spot_num{
+            winners.push((draw_random(seed, i) % ticket_num as u64) as u32);
+        }
+        return Ok(winners);
+    }
+    if selection_mode == SELECTION_DISTINCT_WALLET {
+        return draw_distinct_wallets(seed, ledger, spot_num);
+    }
+    let mut swaps : BTreeMap<u32, u32> = BTreeMap::new();
+    let mut step = 0;
+    while winners.len() < spot_num as usize && step < ticket_num {
+        let j = step + (draw_random(seed, step) % (ticket_num - step) as u64) as u32;
+        let ticket = *swaps.get(&j).unwrap_or(&j);
+        let current = *swaps.get(&step).unwrap_or(&step);
+        swaps.insert(j, current);
+        step += 1;
+        winners.push(ticket);
+    }
+    winners.resize(spot_num as usize, NO_WINNER);
+    Ok(winners)
+}
+
+/// Distinct-wallet part of `draw_winners`. Every spot picks a ticket
+/// uniformly among the tickets of wallets not yet picked.
+fn draw_distinct_wallets(
+    seed : &[u8;32],
+    ledger : &AccountInfo,
+    spot_num : u32,
+    ) -> core::result::Result<Vec<u32>, ProgramError> {
+    let data = ledger.data.borrow();
+    let range_num = load_header::<Ledger>(&data)?.range_num as usize;
+    let ranges = load_entries::<Ledger, TicketRange>(&data)
+        .get(..range_num)
+        .ok_or(PoolError::InvalidStoreAccount)?;
+    let mut tickets : BTreeMap<Pubkey, u64> = BTreeMap::new();
+    let mut start = 0;
+    for range in ranges {
+        *tickets.entry(range.buyer).or_insert(0) += range.end.saturating_sub(start) as u64;
+        start = range.end;
+    }
+    let mut remaining : u64 = tickets.values().sum();
+    let mut picked : BTreeSet<Pubkey> = BTreeSet::new();
+    let mut winners : Vec<u32> = vec![];
+    for i in 0..spot_num{
+        if remaining == 0 {
+            winners.push(NO_WINNER);
+            continue;
+        }
+        let mut target = draw_random(seed, i) % remaining;
+        let mut start = 0;
+        for range in ranges {
+            let len = range.end.saturating_sub(start) as u64;
+            if !picked.contains(&range.buyer) {
+                if target < len {
+                    winners.push(start + target as u32);
+                    picked.insert(range.buyer);
+                    remaining -= tickets[&range.buyer];
+                    break;
+                }
+                target -= len;
+            }
+            start = range.end;
+        }
+    }
+    Ok(winners)
+}
+
+/// Splits a raffle's proceeds into the protocol fee and the creator's
+/// share. The fee rounds down, so tiny amounts go entirely to the creator.
+pub fn split_fee(
+    amount : u64,
+    fee_bps : u16,
+    ) -> (u64, u64) {
+    let fee = (amount as u128 * fee_bps as u128 / FEE_BPS_DENOMINATOR as u128) as u64;
+    (fee, amount - fee)
+}
+
+/// Checks an unchecked account is an SPL token account with the given
+/// owner and mint.
+pub fn check_token_account(
+    a : &AccountInfo,
+    owner : &Pubkey,
+    mint : &Pubkey,
+    ) -> ProgramResult {
+    let token_account = Account::<TokenAccount>::try_from(a)?;
+    if token_account.owner != *owner || token_account.mint != *mint {
+        return Err(PoolError::NotMatch.into());
+    }
+    Ok(())
+}
+
+/// True when `member` may use instructions gated on `permission`: either
+/// it owns the raffle system, or `role` is its role there with that bit.
+/// The owner can pass any account as `role`.
+pub fn has_permission(
+    raffle_system : &ProgramAccount<RaffleSystem>,
+    member : &Pubkey,
+    role : &AccountInfo,
+    permission : u32,
+    ) -> bool {
+    if raffle_system.owner == *member {
+        return true;
+    }
+    match ProgramAccount::<Role>::try_from(&ID, role) {
+        Ok(role) => role.raffle_system == raffle_system.key()
+            && role.member == *member
+            && role.permissions & permission == permission,
+        Err(_) => false,
+    }
+}
+
+/// True when `a` is a ledger or spot store owned by this program, tagged
+/// with `discriminator` and written for `raffle`.
+pub fn is_raffle_store(
+    a : &AccountInfo,
+    discriminator : [u8;8],
+    raffle : &Pubkey,
+    ) -> bool {
+    if *a.owner != ID || a.data.borrow().get(..8) != Some(&discriminator[..]) {
+        return false;
+    }
+    get_raffle_address(a).ok() == Some(*raffle)
+}
+
+/// Fails unless every spot has been claimed or redeemed, i.e. no NFT is
+/// left in escrow. Redeeming clears the spot's mint.
+pub fn check_spots_closed(
+    spot_store : &AccountInfo,
+    ) -> ProgramResult {
+    let data = spot_store.data.borrow();
+    let spot_num = load_header::<SpotStore>(&data)?.spot_num as usize;
+    let spots = load_entries::<SpotStore, Spot>(&data);
+    if spots.iter().take(spot_num).any(|spot| spot.nft != Pubkey::default() && spot.claimed == 0) {
+        msg!("Spot not claimed or redeemed");
+        return Err(PoolError::EscrowNotEmpty.into());
+    }
+    Ok(())
+}
+
+/// True when a spot holding an NFT has no winner, i.e. a manual award
+/// raffle was ended before `end_state` set its winners.
+pub fn has_unawarded_spot(
+    spot_store : &AccountInfo,
+    ) -> core::result::Result<bool, ProgramError> {
+    let data = spot_store.data.borrow();
+    let spot_num = load_header::<SpotStore>(&data)?.spot_num as usize;
+    let spots = load_entries::<SpotStore, Spot>(&data);
+    Ok(spots.iter().take(spot_num).any(|spot| spot.nft != Pubkey::default() && spot.winner_ticket == NO_WINNER))
 }
 
 pub fn get_spot(
     a : &AccountInfo,
     index : usize
     ) -> core::result::Result<Spot, ProgramError>{
-    let arr = a.data.borrow();
-    let data_array = &arr[44+index*SPOT_SIZE..44+index*SPOT_SIZE+SPOT_SIZE];
-    let spot : Spot = Spot::try_from_slice(data_array)?;
-    Ok(spot)
+    let data = a.data.borrow();
+    load_entries::<SpotStore, Spot>(&data).get(index).copied().ok_or_else(|| PoolError::InvalidIndex.into())
 }
 
 #[error]
@@ -960,5 +2625,946 @@ pub enum PoolError{
     InsufficentFunds,
 
     #[msg("sol transfer failed")]
-    SolTransferFailed
-}
\ No newline at end of file
+    SolTransferFailed,
+
+    #[msg("Revealed secret does not match commitment")]
+    InvalidReveal,
+
+    #[msg("Raffle must be ended by reveal")]
+    RevealRequired,
+
+    #[msg("Invalid slot hashes")]
+    InvalidSlotHashes,
+
+    #[msg("Raffle must be ended by oracle randomness")]
+    OracleRequested,
+
+    #[msg("Invalid oracle")]
+    InvalidOracle,
+
+    #[msg("Stale oracle result")]
+    StaleOracleResult,
+
+    #[msg("Invalid selection mode")]
+    InvalidSelectionMode,
+
+    #[msg("Manual award is disabled for this raffle")]
+    ManualAwardDisabled,
+
+    #[msg("Already refunded")]
+    AlreadyRefunded,
+
+    #[msg("Invalid fee")]
+    InvalidFee,
+
+    #[msg("Raffle system is paused")]
+    Paused,
+
+    #[msg("Raffle period has not elapsed")]
+    RaffleNotEnded,
+
+    #[msg("Invalid ledger or spot store account")]
+    InvalidStoreAccount,
+
+    #[msg("Ledger or spot store too large")]
+    StoreTooLarge,
+
+    #[msg("Ledger is full")]
+    LedgerFull,
+
+    #[msg("Escrow not empty")]
+    EscrowNotEmpty,
+
+    #[msg("Manual award cannot be combined with a random draw")]
+    InvalidManualAward,
+
+    #[msg("Raffle needs a commitment, an oracle or manual award")]
+    NoRandomnessSource,
+
+    #[msg("Invalid ticket number")]
+    InvalidTicketNum
+}
+
+// These tests drive instructions through `entry` with in-memory accounts.
+// There is no bank behind them, so cross-program invocations are no-ops
+// and token or lamport movements made through them are not observed.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::{Accounts, AccountsExit, InstructionData, solana_program::{bpf_loader, system_program, sysvar::Sysvar}};
+    use anchor_lang::__private::ErrorCode;
+
+    fn new_account(
+        key : Pubkey,
+        owner : Pubkey,
+        data : Vec<u8>,
+        is_signer : bool,
+        executable : bool,
+        ) -> AccountInfo<'static> {
+        AccountInfo::new(
+            Box::leak(Box::new(key)),
+            is_signer,
+            true,
+            Box::leak(Box::new(1_000_000_000)),
+            Box::leak(data.into_boxed_slice()),
+            Box::leak(Box::new(owner)),
+            executable,
+            0,
+        )
+    }
+
+    fn account(
+        key : Pubkey,
+        owner : Pubkey,
+        data : Vec<u8>,
+        ) -> AccountInfo<'static> {
+        new_account(key, owner, data, false, false)
+    }
+
+    fn signer() -> AccountInfo<'static> {
+        new_account(Pubkey::new_unique(), system_program::ID, vec![], true, false)
+    }
+
+    fn program(
+        id : Pubkey,
+        ) -> AccountInfo<'static> {
+        new_account(id, bpf_loader::ID, vec![], false, true)
+    }
+
+    /// Placeholder for accounts an instruction does not use on its path.
+    fn unused() -> AccountInfo<'static> {
+        account(Pubkey::new_unique(), system_program::ID, vec![])
+    }
+
+    fn program_account<T : AccountSerialize>(
+        key : Pubkey,
+        owner : Pubkey,
+        value : &T,
+        size : usize,
+        ) -> AccountInfo<'static> {
+        let mut data = vec![];
+        value.try_serialize(&mut data).unwrap();
+        data.resize(8 + size, 0);
+        account(key, owner, data)
+    }
+
+    fn read<T : AccountDeserialize>(
+        info : &AccountInfo,
+        ) -> T {
+        T::try_deserialize(&mut &info.data.borrow()[..]).unwrap()
+    }
+
+    fn write<T : AccountSerialize>(
+        info : &AccountInfo,
+        value : &T,
+        ) {
+        let mut data = info.data.borrow_mut();
+        value.try_serialize(&mut &mut data[..]).unwrap();
+    }
+
+    fn clock_account(
+        unix_timestamp : i64,
+        slot : u64,
+        ) -> AccountInfo<'static> {
+        let mut info = account(sysvar::clock::ID, sysvar::ID, vec![0; Clock::size_of()]);
+        Clock{ slot, unix_timestamp, ..Clock::default() }.to_account_info(&mut info).unwrap();
+        info
+    }
+
+    /// Runs `ix` through the program entrypoint with `accounts` in the
+    /// order of its `#[derive(Accounts)]` struct.
+    fn run(
+        ix : impl InstructionData,
+        accounts : &[&AccountInfo<'static>],
+        ) -> ProgramResult {
+        let infos : Vec<AccountInfo> = accounts.iter().map(|a| (*a).clone()).collect();
+        entry(&ID, &infos, &ix.data())
+    }
+
+    /// Raffle system owned by `owner` with an open raffle of `purchases`
+    /// and `spot_num` empty spots. The sale runs from 0 to 100.
+    struct Fixture{
+        owner : AccountInfo<'static>,
+        raffle_system : AccountInfo<'static>,
+        raffle : AccountInfo<'static>,
+        ledger : AccountInfo<'static>,
+        spot_store : AccountInfo<'static>,
+    }
+
+    fn fixture(
+        purchases : &[(Pubkey, u32)],
+        spot_num : u32,
+        ) -> Fixture {
+        let owner = signer();
+        let raffle_system_key = Pubkey::new_unique();
+        let raffle_key = Pubkey::new_unique();
+        let ledger = ledger_account(&raffle_key, purchases, 1);
+        let spot_store = spot_store_account(&raffle_key, spot_num);
+        let raffle_system = program_account(raffle_system_key, ID, &RaffleSystem{
+            owner : *owner.key,
+            manager : *owner.key,
+            ..RaffleSystem::default()
+        }, RAFFLE_SYSTEM_SIZE);
+        let raffle = program_account(raffle_key, ID, &Raffle{
+            raffle_system : raffle_system_key,
+            status : RaffleStatus::Open,
+            spot_num,
+            max_ticket_num : 10_000,
+            period : 100,
+            ledger_account : *ledger.key,
+            spots_account : *spot_store.key,
+            payment_mint : native_mint::ID,
+            creator : *owner.key,
+            ..Raffle::default()
+        }, RAFFLE_SIZE);
+        Fixture{ owner, raffle_system, raffle, ledger, spot_store }
+    }
+
+    fn spot_store_account(
+        raffle : &Pubkey,
+        spot_num : u32,
+        ) -> AccountInfo<'static> {
+        let mut data = vec![0u8; spot_store_size(spot_num)];
+        data[..8].copy_from_slice(&SpotStore::discriminator());
+        let info = account(Pubkey::new_unique(), ID, data);
+        {
+            let mut data = info.data.borrow_mut();
+            let header = load_header_mut::<SpotStore>(&mut data).unwrap();
+            header.raffle_account = *raffle;
+            header.spot_num = spot_num;
+        }
+        info
+    }
+
+    fn oracle_account(
+        authority : &Pubkey,
+        ) -> AccountInfo<'static> {
+        program_account(Pubkey::new_unique(), mock_oracle::ID, &mock_oracle::OracleResult{
+            authority : *authority,
+            slot : 0,
+            result : [0;32],
+        }, mock_oracle::ORACLE_RESULT_SIZE)
+    }
+
+    /// Runs the mock oracle's `fulfill` handler with its own account checks.
+    fn fulfill(
+        authority : &AccountInfo<'static>,
+        oracle : &AccountInfo<'static>,
+        clock : &AccountInfo<'static>,
+        result : [u8;32],
+        ) -> ProgramResult {
+        let infos = [authority.clone(), oracle.clone(), clock.clone()];
+        let mut remaining : &[AccountInfo] = &infos;
+        let mut accounts = mock_oracle::Fulfill::try_accounts(&mock_oracle::ID, &mut remaining, &[])?;
+        mock_oracle::mock_oracle::fulfill(Context::new(&mock_oracle::ID, &mut accounts, remaining), result)?;
+        accounts.exit(&mock_oracle::ID)
+    }
+
+    /// Ledger of `raffle` where each `(buyer, num)` bought `num` tickets in
+    /// turn, with `spare` free ranges after them.
+    fn ledger_account(
+        raffle : &Pubkey,
+        purchases : &[(Pubkey, u32)],
+        spare : u32,
+        ) -> AccountInfo<'static> {
+        let mut data = vec![0u8; ledger_size(purchases.len() as u32 + spare)];
+        data[..8].copy_from_slice(&Ledger::discriminator());
+        let mut info = account(Pubkey::new_unique(), ID, data);
+        load_header_mut::<Ledger>(&mut info.data.borrow_mut()).unwrap().raffle_account = *raffle;
+        for (buyer, num) in purchases {
+            push_tickets(&mut info, *buyer, *num).unwrap();
+        }
+        info
+    }
+
+    fn slot_hashes_account(
+        entries : &[(u64, [u8;32])],
+        ) -> AccountInfo<'static> {
+        let mut data = (entries.len() as u64).to_le_bytes().to_vec();
+        for (slot, hash) in entries {
+            data.extend_from_slice(&slot.to_le_bytes());
+            data.extend_from_slice(hash);
+        }
+        account(sysvar::slot_hashes::ID, sysvar::ID, data)
+    }
+
+    #[test]
+    fn reveal_draw_depends_only_on_secret_and_slot_hash() {
+        let raffle = Pubkey::new_unique();
+        let purchases : Vec<(Pubkey, u32)> = (0..20).map(|i| (Pubkey::new_unique(), i + 1)).collect();
+        let ledger = ledger_account(&raffle, &purchases, 0);
+        let ticket_num = get_num(&ledger).unwrap() as u32;
+        let secret = [7u8;32];
+        for mode in [SELECTION_INDEPENDENT, SELECTION_DISTINCT_TICKET, SELECTION_DISTINCT_WALLET] {
+            let first = draw_winners(&reveal_seed(&secret, &[1;32]), &ledger, ticket_num, 5, mode).unwrap();
+            let again = draw_winners(&reveal_seed(&secret, &[1;32]), &ledger, ticket_num, 5, mode).unwrap();
+            let other = draw_winners(&reveal_seed(&secret, &[2;32]), &ledger, ticket_num, 5, mode).unwrap();
+            assert_eq!(first, again);
+            assert_ne!(first, other);
+        }
+        assert_ne!(reveal_seed(&secret, &[1;32]), reveal_seed(&[8;32], &[1;32]));
+    }
+
+    #[test]
+    fn reveal_uses_the_recorded_slot_only() {
+        let slot_hashes = slot_hashes_account(&[(12, [3;32]), (11, [2;32]), (10, [1;32])]);
+        assert_eq!(get_slot_hash(&slot_hashes, 11), Ok([2;32]));
+        assert_eq!(get_slot_hash(&slot_hashes, 13), Err(PoolError::InvalidSlotHashes.into()));
+        assert_eq!(get_slot_hash(&slot_hashes, 9), Err(PoolError::InvalidSlotHashes.into()));
+    }
+
+    #[test]
+    fn oracle_flow_draws_once_from_the_pinned_oracle() {
+        let buyer = Pubkey::new_unique();
+        let f = fixture(&[(buyer, 3), (Pubkey::new_unique(), 4)], 2);
+        let authority = signer();
+        let oracle = oracle_account(authority.key);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.oracle_program = mock_oracle::ID;
+        raffle_system.oracle_account = *oracle.key;
+        write(&f.raffle_system, &raffle_system);
+        let request = |clock : &AccountInfo<'static>, oracle : &AccountInfo<'static>| run(
+            instruction::RequestRandomness{},
+            &[&f.owner, &f.raffle_system, &f.raffle, oracle, clock],
+        );
+        let consume = |clock : &AccountInfo<'static>| run(
+            instruction::ConsumeRandomness{},
+            &[&f.owner, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger, &oracle, &unused(), &unused(), &program(token::ID), clock],
+        );
+
+        assert_eq!(request(&clock_account(100, 10), &oracle), Err(PoolError::RaffleNotEnded.into()));
+        assert_eq!(request(&clock_account(101, 10), &oracle_account(authority.key)), Err(PoolError::InvalidOracle.into()));
+        request(&clock_account(101, 10), &oracle).unwrap();
+        assert_eq!(consume(&clock_account(102, 10)), Err(PoolError::StaleOracleResult.into()));
+
+        fulfill(&authority, &oracle, &clock_account(102, 11), [9;32]).unwrap();
+        assert_eq!(request(&clock_account(103, 12), &oracle), Err(PoolError::OracleRequested.into()));
+        consume(&clock_account(103, 12)).unwrap();
+
+        let raffle = read::<Raffle>(&f.raffle);
+        assert_eq!(raffle.status, RaffleStatus::Drawn);
+        let winners = draw_winners(&[9;32], &f.ledger, 7, 2, SELECTION_INDEPENDENT).unwrap();
+        for (i, winner) in winners.iter().enumerate() {
+            assert_eq!({ get_spot(&f.spot_store, i).unwrap().winner_ticket }, *winner);
+        }
+    }
+
+    #[test]
+    fn oracle_result_cannot_be_consumed_during_the_sale() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let authority = signer();
+        let oracle = oracle_account(authority.key);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.oracle_program = mock_oracle::ID;
+        raffle_system.oracle_account = *oracle.key;
+        write(&f.raffle_system, &raffle_system);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.oracle = *oracle.key;
+        write(&f.raffle, &raffle);
+        fulfill(&authority, &oracle, &clock_account(50, 11), [9;32]).unwrap();
+        assert_eq!(run(
+            instruction::ConsumeRandomness{},
+            &[&f.owner, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger, &oracle, &unused(), &unused(), &program(token::ID), &clock_account(60, 12)],
+        ), Err(PoolError::RaffleNotEnded.into()));
+    }
+
+    #[test]
+    fn wallet_draw_awards_every_spot_while_wallets_remain() {
+        let whale = Pubkey::new_unique();
+        let minnow = Pubkey::new_unique();
+        let ledger = ledger_account(&Pubkey::new_unique(), &[(whale, 90), (minnow, 5), (whale, 4), (Pubkey::new_unique(), 1)], 0);
+        for seed in 0..64u8 {
+            let winners = draw_winners(&[seed;32], &ledger, 100, 4, SELECTION_DISTINCT_WALLET).unwrap();
+            assert_eq!(winners[3], NO_WINNER);
+            let mut owners = BTreeSet::new();
+            for winner in &winners[..3] {
+                assert!(owners.insert(get_user(&ledger, *winner as usize).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn wallet_draw_follows_ticket_weight() {
+        let whale = Pubkey::new_unique();
+        let ledger = ledger_account(&Pubkey::new_unique(), &[(whale, 90), (Pubkey::new_unique(), 10)], 0);
+        let whale_first = (0..=255u8)
+            .filter(|seed| get_user(&ledger, draw_winners(&[*seed;32], &ledger, 100, 1, SELECTION_DISTINCT_WALLET).unwrap()[0] as usize).unwrap() == whale)
+            .count();
+        assert!((200..=250).contains(&whale_first), "{}", whale_first);
+    }
+
+    #[test]
+    fn end_state_only_sets_winners_of_an_open_manual_raffle() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 2);
+        let end_state = |ticket_count : u64| run(
+            instruction::EndState{ _ticket_count : ticket_count },
+            &[&f.owner, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger],
+        );
+        let winner_tickets = || (0..2).map(|i| get_spot(&f.spot_store, i).unwrap().winner_ticket).collect::<Vec<u32>>();
+        set_winner(&mut f.spot_store.clone(), vec![NO_WINNER, NO_WINNER]).unwrap();
+
+        assert_eq!(end_state(1), Err(PoolError::ManualAwardDisabled.into()));
+        assert_eq!(winner_tickets(), vec![NO_WINNER, NO_WINNER]);
+
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.manual_award = true;
+        raffle.status = RaffleStatus::Drawn;
+        write(&f.raffle, &raffle);
+        assert_eq!(end_state(1), Err(PoolError::InvalidStatus.into()));
+        assert_eq!(winner_tickets(), vec![NO_WINNER, NO_WINNER]);
+
+        raffle.status = RaffleStatus::Open;
+        write(&f.raffle, &raffle);
+        end_state(0).unwrap();
+        assert_eq!(winner_tickets(), vec![0, 0]);
+    }
+
+    #[test]
+    fn manual_award_cannot_be_revealed() {
+        let f = fixture(&[], 1);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.manual_award = true;
+        raffle.status = RaffleStatus::Pending;
+        write(&f.raffle, &raffle);
+        assert_eq!(run(
+            instruction::StartRaffle{ _period : 100, _commitment : [1;32] },
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle, &clock_account(0, 1)],
+        ), Err(PoolError::InvalidManualAward.into()));
+    }
+
+    fn user_data_account(
+        owner : &Pubkey,
+        raffle : &Pubkey,
+        ) -> AccountInfo<'static> {
+        program_account(Pubkey::new_unique(), ID, &UserData{
+            owner : *owner,
+            raffle : *raffle,
+            ticket_num : 0,
+            bump : 0,
+            refunded : false,
+        }, USERDATA_SIZE)
+    }
+
+    fn buy_ticket(
+        f : &Fixture,
+        raffle_system : &AccountInfo<'static>,
+        user_data : &AccountInfo<'static>,
+        num : u32,
+        ) -> ProgramResult {
+        let unused = account(Pubkey::new_unique(), system_program::ID, vec![]);
+        run(
+            instruction::BuyTicket{ _num : num },
+            &[&f.owner, raffle_system, &f.raffle, user_data, &f.ledger, &unused, &unused,
+                &program(token::ID), &program(system_program::ID), &clock_account(50, 1)],
+        )
+    }
+
+    #[test]
+    fn sol_ticket_purchase_takes_any_token_accounts() {
+        let f = fixture(&[], 1);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.ticket_value = 10;
+        write(&f.raffle, &raffle);
+        let user_data = user_data_account(f.owner.key, f.raffle.key);
+        buy_ticket(&f, &f.raffle_system, &user_data, 2).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).sol_balance, 20);
+        assert_eq!(get_num(&f.ledger).unwrap(), 2);
+    }
+
+    fn token_account(
+        owner : &Pubkey,
+        mint : &Pubkey,
+        amount : u64,
+        ) -> AccountInfo<'static> {
+        token_account_at(Pubkey::new_unique(), owner, mint, amount)
+    }
+
+    fn token_account_at(
+        key : Pubkey,
+        owner : &Pubkey,
+        mint : &Pubkey,
+        amount : u64,
+        ) -> AccountInfo<'static> {
+        let mut data = vec![0u8; spl_token::state::Account::LEN];
+        spl_token::state::Account{
+            mint : *mint,
+            owner : *owner,
+            amount,
+            state : spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        }.pack_into_slice(&mut data);
+        account(key, token::ID, data)
+    }
+
+    #[test]
+    fn split_fee_rounds_the_fee_down() {
+        assert_eq!(split_fee(0, 500), (0, 0));
+        assert_eq!(split_fee(19, 500), (0, 19));
+        assert_eq!(split_fee(20, 500), (1, 19));
+        assert_eq!(split_fee(10_000, 1), (1, 9_999));
+        assert_eq!(split_fee(9_999, 1), (0, 9_999));
+        assert_eq!(split_fee(1_000, 0), (0, 1_000));
+        assert_eq!(split_fee(u64::MAX, MAX_FEE_BPS), (u64::MAX / 10, u64::MAX - u64::MAX / 10));
+    }
+
+    #[test]
+    fn token_settlement_pays_the_treasury_only_when_opted_in() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let mint = Pubkey::new_unique();
+        let escrow = token_account(f.raffle_system.key, &mint, 30);
+        let treasury = token_account(f.raffle_system.key, &mint, 0);
+        let creator_account = token_account(f.owner.key, &mint, 0);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.token_account = *treasury.key;
+        write(&f.raffle_system, &raffle_system);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.status = RaffleStatus::Drawn;
+        raffle.payment_mint = mint;
+        raffle.escrow_account = *escrow.key;
+        write(&f.raffle, &raffle);
+        let settle = |token_to : &AccountInfo<'static>| run(
+            instruction::SettleRaffle{},
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle, &escrow, token_to, token_to, &program(token::ID)],
+        );
+
+        assert_eq!(settle(&treasury), Err(PoolError::NotMatch.into()));
+        settle(&creator_account).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).settled_revenue, 0);
+
+        raffle.treasury_proceeds = true;
+        write(&f.raffle, &raffle);
+        assert_eq!(settle(&creator_account), Err(PoolError::NotMatch.into()));
+        settle(&treasury).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).settled_revenue, 30);
+    }
+
+    #[test]
+    fn ticket_purchase_needs_the_raffles_own_unpaused_system() {
+        let f = fixture(&[], 1);
+        let user_data = user_data_account(f.owner.key, f.raffle.key);
+        let foreign_system = program_account(Pubkey::new_unique(), ID, &RaffleSystem::default(), RAFFLE_SYSTEM_SIZE);
+        assert_eq!(buy_ticket(&f, &foreign_system, &user_data, 1), Err(ErrorCode::ConstraintHasOne.into()));
+
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.pause_flag = true;
+        write(&f.raffle_system, &raffle_system);
+        assert_eq!(buy_ticket(&f, &f.raffle_system, &user_data, 1), Err(PoolError::Paused.into()));
+        assert_eq!(get_num(&f.ledger).unwrap(), 0);
+
+        raffle_system.pause_flag = false;
+        write(&f.raffle_system, &raffle_system);
+        buy_ticket(&f, &f.raffle_system, &user_data, 1).unwrap();
+        assert_eq!(get_num(&f.ledger).unwrap(), 1);
+    }
+
+    #[test]
+    fn restarting_a_raffle_clears_its_oracle_request() {
+        let f = fixture(&[], 1);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.status = RaffleStatus::Pending;
+        raffle.oracle = Pubkey::new_unique();
+        raffle.oracle_request_slot = 5;
+        write(&f.raffle, &raffle);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.oracle_program = mock_oracle::ID;
+        write(&f.raffle_system, &raffle_system);
+        run(
+            instruction::StartRaffle{ _period : 100, _commitment : [0;32] },
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle, &clock_account(0, 6)],
+        ).unwrap();
+        let raffle = read::<Raffle>(&f.raffle);
+        assert_eq!(raffle.oracle, Pubkey::default());
+        assert_eq!(raffle.oracle_request_slot, 0);
+    }
+
+    fn end_raffle(
+        f : &Fixture,
+        cranker : &AccountInfo<'static>,
+        clock : &AccountInfo<'static>,
+        ) -> ProgramResult {
+        let unused = account(Pubkey::new_unique(), system_program::ID, vec![]);
+        run(
+            instruction::EndRaffle{},
+            &[cranker, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger, &unused, &unused, &program(token::ID), clock],
+        )
+    }
+
+    #[test]
+    fn end_raffle_never_draws_random_winners() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 2);
+        set_winner(&mut f.spot_store.clone(), vec![NO_WINNER, NO_WINNER]).unwrap();
+        let cranker = signer();
+        assert_eq!(end_raffle(&f, &cranker, &clock_account(100, 1)), Err(PoolError::RaffleNotEnded.into()));
+        assert_eq!(end_raffle(&f, &cranker, &clock_account(101, 1)), Err(PoolError::OracleRequested.into()));
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Open);
+
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.manual_award = true;
+        write(&f.raffle, &raffle);
+        set_winner(&mut f.spot_store.clone(), vec![2]).unwrap();
+        end_raffle(&f, &cranker, &clock_account(101, 1)).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Drawn);
+        assert_eq!({ get_spot(&f.spot_store, 0).unwrap().winner_ticket }, 2);
+        assert_eq!({ get_spot(&f.spot_store, 1).unwrap().winner_ticket }, NO_WINNER);
+    }
+
+    #[test]
+    fn end_raffle_fails_an_undersold_raffle_without_a_draw() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.min_ticket_num = 4;
+        write(&f.raffle, &raffle);
+        end_raffle(&f, &signer(), &clock_account(101, 1)).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Failed);
+    }
+
+    #[test]
+    fn raffle_cannot_start_without_a_randomness_source() {
+        let f = fixture(&[], 1);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.status = RaffleStatus::Pending;
+        write(&f.raffle, &raffle);
+        let start = |commitment : [u8;32]| run(
+            instruction::StartRaffle{ _period : 100, _commitment : commitment },
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle, &clock_account(0, 1)],
+        );
+        assert_eq!(start([0;32]), Err(PoolError::NoRandomnessSource.into()));
+        start([1;32]).unwrap();
+    }
+
+    #[test]
+    fn status_transitions_follow_the_table() {
+        use RaffleStatus::*;
+        let statuses = [Pending, Open, Drawn, Cancelled, Failed];
+        // Rows are `from`, columns are `to`, both in the order of `statuses`.
+        let allowed = [
+            [false, true, false, true, false],
+            [true, false, true, true, true],
+            [false, false, false, false, false],
+            [false, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (from, row) in statuses.iter().zip(allowed.iter()) {
+            for (to, expected) in statuses.iter().zip(row.iter()) {
+                assert_eq!(from.can_transition(*to), *expected, "{:?} -> {:?}", from, to);
+                let mut raffle = Raffle{ status : *from, ..Raffle::default() };
+                let result = raffle.transition(*to);
+                if *expected {
+                    assert_eq!(result, Ok(()));
+                    assert_eq!(raffle.status, *to);
+                } else {
+                    assert_eq!(result, Err(PoolError::InvalidStatus.into()));
+                    assert_eq!(raffle.status, *from);
+                }
+            }
+        }
+    }
+
+    /// Copy of `info` laid out the way the loader serializes it: the data
+    /// length in the 8 bytes before the data and MAX_PERMITTED_DATA_INCREASE
+    /// spare bytes after it, which `resize_store` relies on.
+    fn loader_account(
+        info : &AccountInfo,
+        ) -> AccountInfo<'static> {
+        let len = info.data_len();
+        let words = Box::leak(vec![0u64; 1 + (len + MAX_PERMITTED_DATA_INCREASE).div_ceil(8)].into_boxed_slice());
+        let buffer : &'static mut [u8] = bytemuck::cast_slice_mut(words);
+        buffer[..8].copy_from_slice(&(len as u64).to_le_bytes());
+        buffer[8..8 + len].copy_from_slice(&info.data.borrow());
+        AccountInfo::new(
+            Box::leak(Box::new(*info.key)),
+            false,
+            true,
+            Box::leak(Box::new(info.lamports())),
+            &mut buffer[8..8 + len],
+            Box::leak(Box::new(*info.owner)),
+            false,
+            0,
+        )
+    }
+
+    fn rent_account() -> AccountInfo<'static> {
+        let mut info = account(sysvar::rent::ID, sysvar::ID, vec![0; Rent::size_of()]);
+        Rent::default().to_account_info(&mut info).unwrap();
+        info
+    }
+
+    #[test]
+    fn grow_ledger_is_bounded_by_tickets_and_the_per_call_increase() {
+        let buyer = Pubkey::new_unique();
+        let f = fixture(&[(buyer, 3)], 1);
+        // Each call gets a fresh loader layout, as every instruction does.
+        let grow = |ledger : &AccountInfo<'static>, range_num : u32| {
+            let ledger = loader_account(ledger);
+            run(
+                instruction::GrowLedger{ _range_num : range_num },
+                &[&f.owner, &f.raffle_system, &f.raffle, &ledger, &program(system_program::ID), &rent_account()],
+            ).map(|_| ledger)
+        };
+        let per_call = (MAX_PERMITTED_DATA_INCREASE / size_of::<TicketRange>()) as u32;
+
+        assert_eq!(grow(&f.ledger, per_call + 1).unwrap_err(), PoolError::StoreTooLarge.into());
+        let ledger = grow(&f.ledger, per_call).unwrap();
+        assert_eq!(ledger.data_len(), ledger_size(2 + per_call));
+        assert_eq!(get_user(&ledger, 2).unwrap(), buyer);
+        assert!(ledger.data.borrow()[ledger_size(2)..].iter().all(|b| *b == 0));
+
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.max_ticket_num = 2 + per_call + 5;
+        write(&f.raffle, &raffle);
+        assert_eq!(grow(&ledger, 6).unwrap_err(), PoolError::StoreTooLarge.into());
+        let ledger = grow(&ledger, 5).unwrap();
+        assert_eq!(ledger.data_len(), ledger_size(raffle.max_ticket_num));
+    }
+
+    #[test]
+    fn resize_store_needs_the_loader_layout() {
+        let ledger = loader_account(&ledger_account(&Pubkey::new_unique(), &[], 1));
+        unsafe {
+            *(ledger.data.borrow_mut().as_mut_ptr().offset(-8) as *mut u64) += 1;
+        }
+        assert_eq!(resize_store(&ledger, ledger_size(2)), Err(PoolError::InvalidStoreAccount.into()));
+        assert_eq!(ledger.data_len(), ledger_size(1));
+    }
+
+    fn mint_account() -> AccountInfo<'static> {
+        let mut data = vec![0u8; spl_token::state::Mint::LEN];
+        spl_token::state::Mint{
+            supply : 1,
+            is_initialized : true,
+            ..spl_token::state::Mint::default()
+        }.pack_into_slice(&mut data);
+        account(Pubkey::new_unique(), token::ID, data)
+    }
+
+    #[test]
+    fn claim_nft_checks_every_token_account() {
+        let f = fixture(&[], 1);
+        push_tickets(&mut f.ledger.clone(), *f.owner.key, 1).unwrap();
+        let nft = mint_account();
+        set_spot(&mut f.spot_store.clone(), 0, Spot{ nft : *nft.key, winner_ticket : 0, claimed : 0 }).unwrap();
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.status = RaffleStatus::Drawn;
+        write(&f.raffle, &raffle);
+        let ata = get_associated_token_address(f.owner.key, nft.key);
+        let nft_from = token_account(f.raffle_system.key, nft.key, 1);
+        let nft_to = token_account_at(ata, f.owner.key, nft.key, 0);
+        let claim = |raffle_system : &AccountInfo<'static>, nft_from : &AccountInfo<'static>, nft_to : &AccountInfo<'static>| run(
+            instruction::ClaimNft{ num : 0 },
+            &[&f.owner, raffle_system, &f.raffle, &f.owner, &f.spot_store, &f.ledger, &nft, nft_from, nft_to,
+                &program(token::ID), &program(spl_associated_token_account::ID), &program(system_program::ID), &rent_account()],
+        );
+
+        let other_mint = Pubkey::new_unique();
+        assert_eq!(claim(&f.raffle_system, &token_account(f.raffle_system.key, &other_mint, 1), &nft_to), Err(ErrorCode::ConstraintRaw.into()));
+        assert_eq!(claim(&f.raffle_system, &token_account(f.owner.key, nft.key, 1), &nft_to), Err(ErrorCode::ConstraintRaw.into()));
+        let someone = Pubkey::new_unique();
+        let someones_ata = token_account_at(get_associated_token_address(&someone, nft.key), &someone, nft.key, 0);
+        assert_eq!(claim(&f.raffle_system, &nft_from, &someones_ata), Err(ErrorCode::ConstraintRaw.into()));
+        assert_eq!(claim(&f.raffle_system, &nft_from, &token_account_at(ata, &someone, nft.key, 0)), Err(PoolError::NotMatch.into()));
+        let foreign_system = program_account(Pubkey::new_unique(), ID, &RaffleSystem::default(), RAFFLE_SYSTEM_SIZE);
+        let foreign_nft_from = token_account(foreign_system.key, nft.key, 1);
+        assert_eq!(claim(&foreign_system, &foreign_nft_from, &nft_to), Err(ErrorCode::ConstraintHasOne.into()));
+        assert_eq!(get_spot(&f.spot_store, 0).unwrap().claimed, 0);
+
+        claim(&f.raffle_system, &nft_from, &nft_to).unwrap();
+        assert_eq!(get_spot(&f.spot_store, 0).unwrap().claimed, 1);
+        assert_eq!(claim(&f.raffle_system, &nft_from, &nft_to), Err(PoolError::AlreadyClaimed.into()));
+    }
+
+    #[test]
+    fn only_the_creator_or_owner_manages_a_raffle() {
+        let f = fixture(&[], 1);
+        let creator = signer();
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.status = RaffleStatus::Pending;
+        raffle.creator = *creator.key;
+        write(&f.raffle, &raffle);
+        let role_for = |member : &AccountInfo<'static>| program_account(Pubkey::new_unique(), ID, &Role{
+            raffle_system : *f.raffle_system.key,
+            member : *member.key,
+            permissions : PERMISSION_CREATE_RAFFLE,
+            bump : 0,
+        }, ROLE_SIZE);
+        let cancel = |owner : &AccountInfo<'static>, role : &AccountInfo<'static>| run(
+            instruction::CancelRaffle{},
+            &[owner, &f.raffle_system, role, &f.raffle],
+        );
+
+        let other_creator = signer();
+        assert_eq!(cancel(&other_creator, &role_for(&other_creator)), Err(ErrorCode::ConstraintRaw.into()));
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Pending);
+        cancel(&creator, &role_for(&creator)).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Cancelled);
+
+        raffle.status = RaffleStatus::Pending;
+        write(&f.raffle, &raffle);
+        cancel(&f.owner, &f.raffle_system).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Cancelled);
+    }
+
+    #[test]
+    fn stale_oracle_result_fails_the_raffle() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let authority = signer();
+        let oracle = oracle_account(authority.key);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.oracle_program = mock_oracle::ID;
+        raffle_system.oracle_account = *oracle.key;
+        write(&f.raffle_system, &raffle_system);
+        run(
+            instruction::RequestRandomness{},
+            &[&f.owner, &f.raffle_system, &f.raffle, &oracle, &clock_account(101, 10)],
+        ).unwrap();
+        fulfill(&authority, &oracle, &clock_account(102, 11), [9;32]).unwrap();
+        run(
+            instruction::ConsumeRandomness{},
+            &[&f.owner, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger, &oracle, &unused(), &unused(), &program(token::ID), &clock_account(200, 12 + ORACLE_MAX_AGE)],
+        ).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Failed);
+        assert_eq!({ get_spot(&f.spot_store, 0).unwrap().winner_ticket }, 0);
+    }
+
+    #[test]
+    fn manual_raffle_without_awards_fails_at_end() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 2);
+        let mut raffle = read::<Raffle>(&f.raffle);
+        raffle.manual_award = true;
+        write(&f.raffle, &raffle);
+        set_spot(&mut f.spot_store.clone(), 0, Spot{ nft : Pubkey::new_unique(), winner_ticket : 1, claimed : 0 }).unwrap();
+        set_spot(&mut f.spot_store.clone(), 1, Spot{ nft : Pubkey::new_unique(), winner_ticket : NO_WINNER, claimed : 0 }).unwrap();
+        end_raffle(&f, &signer(), &clock_account(101, 1)).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Failed);
+    }
+
+    #[test]
+    fn raffle_cannot_be_cancelled_once_the_draw_started() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let cancel = || run(
+            instruction::CancelRaffle{},
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle],
+        );
+        let open = read::<Raffle>(&f.raffle);
+        write(&f.raffle, &Raffle{ reveal_slot : 7, ..read::<Raffle>(&f.raffle) });
+        assert_eq!(cancel(), Err(PoolError::InvalidStatus.into()));
+        write(&f.raffle, &Raffle{ oracle : Pubkey::new_unique(), ..read::<Raffle>(&f.raffle) });
+        write(&f.raffle, &Raffle{ reveal_slot : 0, ..read::<Raffle>(&f.raffle) });
+        assert_eq!(cancel(), Err(PoolError::InvalidStatus.into()));
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Open);
+        write(&f.raffle, &open);
+        cancel().unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Cancelled);
+
+        // A raffle that sold nothing goes back to pending with its old
+        // draw state, and can still be cancelled from there.
+        write(&f.raffle, &Raffle{ status : RaffleStatus::Pending, reveal_slot : 7, ..open });
+        cancel().unwrap();
+    }
+
+    #[test]
+    fn ticket_price_overflow_is_rejected() {
+        let f = fixture(&[], 1);
+        write(&f.raffle, &Raffle{ ticket_value : u64::MAX / 2 + 1, ..read::<Raffle>(&f.raffle) });
+        let user_data = user_data_account(f.owner.key, f.raffle.key);
+        assert_eq!(buy_ticket(&f, &f.raffle_system, &user_data, 2), Err(PoolError::NumericalOverflowError.into()));
+        buy_ticket(&f, &f.raffle_system, &user_data, 1).unwrap();
+        assert_eq!(buy_ticket(&f, &f.raffle_system, &user_data, 1), Err(PoolError::NumericalOverflowError.into()));
+        assert_eq!(get_num(&f.ledger).unwrap(), 1);
+    }
+
+    #[test]
+    fn undrawn_oracle_raffle_fails_after_the_timeout() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let deadline = (100 + DRAW_TIMEOUT) as i64;
+        assert_eq!(end_raffle(&f, &signer(), &clock_account(deadline, 1)), Err(PoolError::OracleRequested.into()));
+        end_raffle(&f, &signer(), &clock_account(deadline + 1, 1)).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Failed);
+    }
+
+    #[test]
+    fn anyone_can_draw_an_oracle_raffle_for_the_crank_reward() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let authority = signer();
+        let oracle = oracle_account(authority.key);
+        let mut raffle_system = read::<RaffleSystem>(&f.raffle_system);
+        raffle_system.oracle_program = mock_oracle::ID;
+        raffle_system.oracle_account = *oracle.key;
+        write(&f.raffle_system, &raffle_system);
+        write(&f.raffle, &Raffle{ sol_balance : 30, crank_reward : 5, ..read::<Raffle>(&f.raffle) });
+        let cranker = signer();
+        run(
+            instruction::RequestRandomness{},
+            &[&cranker, &f.raffle_system, &f.raffle, &oracle, &clock_account(101, 10)],
+        ).unwrap();
+        fulfill(&authority, &oracle, &clock_account(102, 11), [9;32]).unwrap();
+        let lamports = cranker.lamports();
+        run(
+            instruction::ConsumeRandomness{},
+            &[&cranker, &f.raffle_system, &f.raffle, &f.spot_store, &f.ledger, &oracle, &unused(), &unused(), &program(token::ID), &clock_account(103, 12)],
+        ).unwrap();
+        let raffle = read::<Raffle>(&f.raffle);
+        assert_eq!(raffle.status, RaffleStatus::Drawn);
+        assert_eq!(raffle.sol_balance, 25);
+        assert_eq!(cranker.lamports(), lamports + 5);
+    }
+
+    #[test]
+    fn stores_are_matched_by_their_own_header() {
+        let raffle = Pubkey::new_unique();
+        let spot_store = spot_store_account(&raffle, 0);
+        assert_eq!(spot_store.data_len(), SPOT_STORE_HEADER_SIZE);
+        assert!(is_raffle_store(&spot_store, SpotStore::discriminator(), &raffle));
+        assert!(!is_raffle_store(&spot_store, Ledger::discriminator(), &raffle));
+        assert!(!is_raffle_store(&spot_store, SpotStore::discriminator(), &Pubkey::new_unique()));
+
+        let ledger = ledger_account(&raffle, &[], 0);
+        assert!(is_raffle_store(&ledger, Ledger::discriminator(), &raffle));
+        assert!(!is_raffle_store(&ledger, SpotStore::discriminator(), &raffle));
+        assert!(!is_raffle_store(&account(Pubkey::new_unique(), ID, vec![]), Ledger::discriminator(), &raffle));
+    }
+
+    #[test]
+    fn empty_purchases_take_no_ledger_range() {
+        let f = fixture(&[], 1);
+        let user_data = user_data_account(f.owner.key, f.raffle.key);
+        assert_eq!(buy_ticket(&f, &f.raffle_system, &user_data, 0), Err(PoolError::InvalidTicketNum.into()));
+
+        let ledger = ledger_account(&Pubkey::new_unique(), &[(Pubkey::new_unique(), 1)], 1);
+        push_tickets(&mut ledger.clone(), Pubkey::new_unique(), 0).unwrap();
+        let header = *load_header::<Ledger>(&ledger.data.borrow()).unwrap();
+        assert_eq!((header.count, header.range_num), (1, 1));
+    }
+
+    #[test]
+    fn creator_reveals_their_own_raffle() {
+        let f = fixture(&[(Pubkey::new_unique(), 3)], 1);
+        let creator = signer();
+        let secret = [4;32];
+        write(&f.raffle, &Raffle{ creator : *creator.key, commitment : hash(&secret).to_bytes(), reveal_slot : 10, ..read::<Raffle>(&f.raffle) });
+        let slot_hashes = slot_hashes_account(&[(10, [1;32])]);
+        let reveal = |owner : &AccountInfo<'static>| run(
+            instruction::RevealRaffle{ _secret : secret },
+            &[owner, &f.raffle_system, &unused(), &f.raffle, &f.spot_store, &f.ledger, &unused(), &unused(), &program(token::ID), &slot_hashes],
+        );
+        assert_eq!(reveal(&signer()), Err(ErrorCode::ConstraintRaw.into()));
+        reveal(&creator).unwrap();
+        assert_eq!(read::<Raffle>(&f.raffle).status, RaffleStatus::Drawn);
+    }
+
+    #[test]
+    fn redeemed_nft_goes_back_to_the_creator() {
+        let f = fixture(&[], 1);
+        let creator = signer();
+        let nft = mint_account();
+        write(&f.raffle, &Raffle{ creator : *creator.key, status : RaffleStatus::Pending, ..read::<Raffle>(&f.raffle) });
+        set_spot(&mut f.spot_store.clone(), 0, Spot{ nft : *nft.key, winner_ticket : NO_WINNER, claimed : 0 }).unwrap();
+        let nft_from = token_account(f.raffle_system.key, nft.key, 1);
+        let redeem = |nft_to : &AccountInfo<'static>| run(
+            instruction::RedeemSpot{ _index : 0 },
+            &[&f.owner, &f.raffle_system, &f.raffle_system, &f.raffle, &creator, &f.spot_store, &nft, &nft_from, nft_to, &program(token::ID)],
+        );
+        assert_eq!(redeem(&token_account(f.owner.key, nft.key, 0)), Err(ErrorCode::ConstraintRaw.into()));
+        redeem(&token_account(creator.key, nft.key, 0)).unwrap();
+        assert_eq!(get_spot(&f.spot_store, 0).unwrap().nft, Pubkey::default());
+    }
+}